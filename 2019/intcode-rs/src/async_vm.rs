@@ -0,0 +1,230 @@
+use crate::{ExecutionError, IntcodeVM};
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::future::poll_fn;
+use std::pin::Pin;
+
+/// An error from running a VM asynchronously
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsyncError<E> {
+    /// The VM itself failed
+    Execution(ExecutionError),
+    /// The output sink failed
+    Sink(E),
+}
+
+impl<E> From<ExecutionError> for AsyncError<E> {
+    fn from(error: ExecutionError) -> Self {
+        AsyncError::Execution(error)
+    }
+}
+
+impl IntcodeVM {
+    /// Run the program until it halts, reading from and writing to async I/O
+    ///
+    /// Values already in the input queue are used first. After that, the VM
+    /// waits on `input` whenever it needs a value, and returns
+    /// `Err(Execution(NeedsInput))` if the stream ends. Every output is sent
+    /// and flushed to `output` as soon as it is produced, so the output queue
+    /// is left empty.
+    ///
+    /// No particular executor is needed, so this can be composed freely with
+    /// other futures (including other VMs).
+    pub async fn run_async<S, K>(
+        &mut self,
+        mut input: S,
+        mut output: K,
+    ) -> Result<(), AsyncError<K::Error>>
+    where
+        S: Stream<Item = i64> + Unpin,
+        K: Sink<i64> + Unpin,
+    {
+        loop {
+            let stepped = self.step();
+
+            while let Some(value) = self.pop_output() {
+                send(&mut output, value).await.map_err(AsyncError::Sink)?;
+            }
+
+            match stepped {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(ExecutionError::NeedsInput) => {
+                    match poll_fn(|cx| Pin::new(&mut input).poll_next(cx)).await {
+                        Some(value) => self.push_input(value),
+                        None => return Err(ExecutionError::NeedsInput.into()),
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+async fn send<K: Sink<i64> + Unpin>(sink: &mut K, value: i64) -> Result<(), K::Error> {
+    poll_fn(|cx| Pin::new(&mut *sink).poll_ready(cx)).await?;
+    Pin::new(&mut *sink).start_send(value)?;
+    poll_fn(|cx| Pin::new(&mut *sink).poll_flush(cx)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct ThreadWaker(std::thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark()
+        }
+    }
+
+    /// Poll every future to completion on the current thread
+    fn block_on_all<T>(mut futures: Vec<Pin<Box<dyn Future<Output = T> + '_>>>) -> Vec<T> {
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut results: Vec<Option<T>> = futures.iter().map(|_| None).collect();
+
+        while results.iter().any(Option::is_none) {
+            let mut progress = false;
+
+            for (future, result) in futures.iter_mut().zip(results.iter_mut()) {
+                if result.is_none() {
+                    if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                        *result = Some(value);
+                        progress = true;
+                    }
+                }
+            }
+
+            if !progress && results.iter().any(Option::is_none) {
+                std::thread::park();
+            }
+        }
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// A single-threaded channel that is both a `Stream` and a `Sink`
+    #[derive(Clone, Default)]
+    struct Pipe(Rc<RefCell<(VecDeque<i64>, Option<Waker>)>>);
+
+    impl Pipe {
+        fn with(values: &[i64]) -> Self {
+            let pipe = Self::default();
+            pipe.0.borrow_mut().0.extend(values);
+            pipe
+        }
+    }
+
+    impl Stream for Pipe {
+        type Item = i64;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<i64>> {
+            let mut inner = self.0.borrow_mut();
+
+            match inner.0.pop_front() {
+                Some(value) => Poll::Ready(Some(value)),
+                None if Rc::strong_count(&self.0) == 1 => Poll::Ready(None),
+                None => {
+                    inner.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    impl Sink<i64> for Pipe {
+        type Error = Infallible;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: i64) -> Result<(), Infallible> {
+            let mut inner = self.0.borrow_mut();
+            inner.0.push_back(item);
+
+            if let Some(waker) = inner.1.take() {
+                waker.wake();
+            }
+
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn echo() {
+        let mut vm = IntcodeVM::new(vec![3, 0, 4, 0, 3, 0, 4, 0, 99]);
+        let output = Pipe::default();
+
+        let results = block_on_all(vec![Box::pin(
+            vm.run_async(Pipe::with(&[5, 6]), output.clone()),
+        )]);
+
+        assert_eq!(results, vec![Ok(())]);
+        assert!(vm.halted());
+        assert_eq!(output.0.borrow().0, vec![5, 6]);
+    }
+
+    #[test]
+    fn stream_ends() {
+        let mut vm = IntcodeVM::new(vec![3, 0, 4, 0, 3, 0, 4, 0, 99]);
+
+        let results = block_on_all(vec![Box::pin(
+            vm.run_async(Pipe::with(&[5]), Pipe::default()),
+        )]);
+
+        assert_eq!(
+            results,
+            vec![Err(AsyncError::Execution(ExecutionError::NeedsInput))]
+        );
+        assert!(!vm.halted());
+    }
+
+    #[test]
+    fn amplifier_chain() {
+        let program = vec![
+            3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0,
+        ];
+        let settings = [4, 3, 2, 1, 0];
+
+        let mut vms = vec![IntcodeVM::new(program); settings.len()];
+        let pipes: Vec<_> = settings.iter().map(|s| Pipe::with(&[*s])).collect();
+        let result = Pipe::default();
+
+        pipes[0].0.borrow_mut().0.push_back(0);
+
+        // Start the VMs in reverse, so every one but the first has to wait
+        let futures = vms
+            .iter_mut()
+            .enumerate()
+            .rev()
+            .map(|(i, vm)| {
+                let output = pipes.get(i + 1).unwrap_or(&result).clone();
+                Box::pin(vm.run_async(pipes[i].clone(), output)) as Pin<Box<dyn Future<Output = _>>>
+            })
+            .collect();
+
+        for finished in block_on_all(futures) {
+            assert_eq!(finished, Ok::<(), AsyncError<Infallible>>(()));
+        }
+
+        assert_eq!(result.0.borrow().0, vec![43210]);
+    }
+}
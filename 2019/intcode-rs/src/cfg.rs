@@ -0,0 +1,577 @@
+use crate::disasm::{self, Instruction};
+use crate::{ExecutionError, Opcode, ParameterMode};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Where control can go after the end of a basic block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    /// Execution continues at the next instruction
+    Next(usize),
+    /// A jump to a known address
+    Jump(usize),
+    /// A call to a known address, which comes back to the block's `Next` exit
+    Call(usize),
+    /// A return through an address on the relative-base stack
+    Return,
+    /// A jump to an address read from memory at runtime
+    Indirect,
+    /// A jump to a negative address, which always fails
+    Invalid(i64),
+}
+
+/// A run of instructions that always execute together, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    /// Where control can go next; empty if the block ends in a halt
+    pub exits: Vec<Exit>,
+}
+
+impl Block {
+    /// Get the address of the first instruction
+    pub fn start(&self) -> usize {
+        self.instructions[0].address
+    }
+
+    /// Get the address just past the end of the last instruction
+    pub fn end(&self) -> usize {
+        self.instructions.last().unwrap().next()
+    }
+}
+
+/// The control-flow graph of a program, found by static analysis
+///
+/// Only instructions reachable from address 0 are decoded, so anything else
+/// is data. Jumps with immediate targets are followed; jumps with position
+/// targets are `Exit::Indirect`, since the target is only known at runtime.
+///
+/// A conditional jump with an immediate condition (such as `1105, 1, x`) is
+/// treated as always or never jumping, unless some reachable instruction
+/// writes to the condition. Writes through pointers that are themselves
+/// changed at runtime aren't noticed, so heavily self-modifying programs may
+/// have edges missing.
+///
+/// Calls and returns are recognized from the usual relative-base convention.
+/// A call stores its return address in a relative-base slot with an `add` or
+/// `mul` of two immediates, then unconditionally jumps to an immediate
+/// address: `21101, ret, 0, 0, 1105, 1, target`. A return is an
+/// unconditional jump to a relative-base slot: `2105, 1, 0`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    blocks: BTreeMap<usize, Block>,
+    invalid: BTreeMap<usize, ExecutionError>,
+    code: BTreeSet<usize>,
+    memory_size: usize,
+}
+
+impl Cfg {
+    /// Analyze a program image
+    pub fn new(memory: &[i64]) -> Self {
+        // Find everything that might be written to, without assuming anything
+        // about conditions, then use that to decide which conditions are constant
+        let (everything, _) = explore(memory, &BTreeSet::new(), false);
+        let written = everything
+            .values()
+            .filter_map(|i| i.as_ref().ok())
+            .filter_map(write_target)
+            .collect();
+
+        let (instructions, leaders) = explore(memory, &written, true);
+
+        let mut cfg = Self {
+            blocks: BTreeMap::new(),
+            invalid: BTreeMap::new(),
+            code: BTreeSet::new(),
+            memory_size: memory.len(),
+        };
+
+        for (address, instruction) in instructions.iter() {
+            match instruction {
+                Ok(instruction) => cfg.code.extend(*address..instruction.next()),
+                Err(e) => {
+                    cfg.invalid.insert(*address, e.clone());
+                }
+            }
+        }
+
+        for leader in leaders.iter() {
+            let mut address = *leader;
+            let mut block = Block {
+                instructions: Vec::new(),
+                exits: Vec::new(),
+            };
+
+            while let Some(Ok(instruction)) = instructions.get(&address) {
+                block.instructions.push(instruction.clone());
+                address = instruction.next();
+
+                if is_block_end(instruction)
+                    || leaders.contains(&address)
+                    || !matches!(instructions.get(&address), Some(Ok(_)))
+                {
+                    block.exits = successors(memory, instruction, &written, true);
+                    break;
+                }
+            }
+
+            if !block.instructions.is_empty() {
+                cfg.blocks.insert(*leader, block);
+            }
+        }
+
+        cfg
+    }
+
+    /// Get every basic block, keyed by its start address
+    pub fn blocks(&self) -> &BTreeMap<usize, Block> {
+        &self.blocks
+    }
+
+    /// Get the block starting at an address
+    pub fn block(&self, start: usize) -> Option<&Block> {
+        self.blocks.get(&start)
+    }
+
+    /// Get the reachable addresses that don't hold a valid instruction, and why
+    pub fn invalid(&self) -> &BTreeMap<usize, ExecutionError> {
+        &self.invalid
+    }
+
+    /// Check if a memory cell is part of a reachable instruction
+    pub fn is_code(&self, address: usize) -> bool {
+        self.code.contains(&address)
+    }
+
+    /// Get the addresses of every cell that isn't part of a reachable instruction
+    pub fn data(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.memory_size).filter(move |a| !self.is_code(*a))
+    }
+
+    /// Disassemble the reachable code, showing everything else as data
+    pub fn listing(&self, memory: &[i64]) -> String {
+        let starts = self.starts();
+        disasm::listing(memory, |address| starts.contains(&address))
+    }
+
+    /// Get each line of `listing`, with the instruction if it's code
+    pub(crate) fn lines(&self, memory: &[i64]) -> Vec<(usize, Option<Instruction>)> {
+        let starts = self.starts();
+        disasm::lines(memory, |address| starts.contains(&address))
+    }
+
+    fn starts(&self) -> BTreeSet<usize> {
+        self.blocks
+            .values()
+            .flat_map(|b| b.instructions.iter().map(|i| i.address))
+            .collect()
+    }
+
+    /// Export the graph in Graphviz DOT format
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        for (start, block) in self.blocks.iter() {
+            let mut label = String::new();
+
+            for instruction in block.instructions.iter() {
+                write!(label, "{}: {}\\l", instruction.address, instruction).unwrap();
+            }
+
+            writeln!(out, "    b{} [label=\"{}\"];", start, label).unwrap();
+
+            for exit in block.exits.iter() {
+                match exit {
+                    Exit::Next(next) => {
+                        writeln!(out, "    b{} -> {};", start, self.node(*next)).unwrap()
+                    }
+                    Exit::Jump(target) => writeln!(
+                        out,
+                        "    b{} -> {} [label=\"jump\"];",
+                        start,
+                        self.node(*target)
+                    )
+                    .unwrap(),
+                    Exit::Call(target) => writeln!(
+                        out,
+                        "    b{} -> {} [label=\"call\", style=bold];",
+                        start,
+                        self.node(*target)
+                    )
+                    .unwrap(),
+                    Exit::Return => writeln!(
+                        out,
+                        "    b{} -> return [label=\"return\", style=dashed];",
+                        start
+                    )
+                    .unwrap(),
+                    Exit::Indirect => writeln!(
+                        out,
+                        "    b{} -> indirect [label=\"jump\", style=dashed];",
+                        start
+                    )
+                    .unwrap(),
+                    Exit::Invalid(target) => writeln!(
+                        out,
+                        "    b{} -> \"invalid {}\" [label=\"jump\"];",
+                        start, target
+                    )
+                    .unwrap(),
+                }
+            }
+        }
+
+        if self
+            .blocks
+            .values()
+            .any(|b| b.exits.contains(&Exit::Indirect))
+        {
+            writeln!(out, "    indirect [label=\"?\", shape=diamond];").unwrap();
+        }
+
+        if self
+            .blocks
+            .values()
+            .any(|b| b.exits.contains(&Exit::Return))
+        {
+            writeln!(out, "    return [shape=oval];").unwrap();
+        }
+
+        for (address, error) in self.invalid.iter() {
+            writeln!(
+                out,
+                "    invalid{} [label=\"{}: {:?}\", color=red];",
+                address, address, error
+            )
+            .unwrap();
+        }
+
+        for block in self.blocks.values() {
+            for exit in block.exits.iter() {
+                if let Exit::Invalid(target) = exit {
+                    writeln!(out, "    \"invalid {}\" [color=red];", target).unwrap();
+                }
+            }
+        }
+
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    fn node(&self, address: usize) -> String {
+        if self.invalid.contains_key(&address) {
+            format!("invalid{}", address)
+        } else {
+            format!("b{}", address)
+        }
+    }
+}
+
+fn is_block_end(instruction: &Instruction) -> bool {
+    matches!(
+        instruction.opcode,
+        Opcode::JumpIfTrue(..) | Opcode::JumpIfFalse(..) | Opcode::Halt
+    )
+}
+
+/// Get the cell an instruction writes to, if it can be known statically
+fn write_target(instruction: &Instruction) -> Option<usize> {
+    let parameter = instruction.write_parameter()?;
+
+    match instruction.opcode.modes()[parameter] {
+        ParameterMode::Position => instruction.parameter_address(parameter),
+        _ => None,
+    }
+}
+
+fn successors(
+    memory: &[i64],
+    instruction: &Instruction,
+    written: &BTreeSet<usize>,
+    fold_conditions: bool,
+) -> Vec<Exit> {
+    let (jump_if, cond_mode, target_mode) = match instruction.opcode {
+        Opcode::Halt => return vec![],
+        Opcode::JumpIfTrue(c, t) => (true, c, t),
+        Opcode::JumpIfFalse(c, t) => (false, c, t),
+        _ => return vec![Exit::Next(instruction.next())],
+    };
+
+    let condition_cell = instruction.address + 1;
+    let constant = fold_conditions
+        && cond_mode == ParameterMode::Immediate
+        && !written.contains(&condition_cell);
+    let jumps = (memory[condition_cell] != 0) == jump_if;
+
+    let mut exits = vec![];
+
+    if constant && jumps {
+        match target_mode {
+            ParameterMode::Relative => return vec![Exit::Return],
+            ParameterMode::Immediate if is_call(memory, instruction) => {
+                let target = instruction.parameters[1] as usize;
+                return vec![Exit::Call(target), Exit::Next(instruction.next())];
+            }
+            _ => {}
+        }
+    }
+
+    if !constant || jumps {
+        exits.push(match target_mode {
+            ParameterMode::Position | ParameterMode::Relative => Exit::Indirect,
+            ParameterMode::Immediate if instruction.parameters[1] < 0 => {
+                Exit::Invalid(instruction.parameters[1])
+            }
+            ParameterMode::Immediate => Exit::Jump(instruction.parameters[1] as usize),
+        });
+    }
+
+    if !constant || !jumps {
+        exits.push(Exit::Next(instruction.next()));
+    }
+
+    exits
+}
+
+/// Check if a jump is the end of a call sequence
+pub(crate) fn is_call(memory: &[i64], jump: &Instruction) -> bool {
+    use ParameterMode::*;
+
+    if jump.address < 4 || jump.parameters[1] < 0 {
+        return false;
+    }
+
+    let ret = match Instruction::decode(memory, jump.address - 4) {
+        Ok(Instruction {
+            opcode: Opcode::Add(Immediate, Immediate, Relative),
+            parameters,
+            ..
+        }) => parameters[0].checked_add(parameters[1]),
+        Ok(Instruction {
+            opcode: Opcode::Multiply(Immediate, Immediate, Relative),
+            parameters,
+            ..
+        }) => parameters[0].checked_mul(parameters[1]),
+        _ => return false,
+    };
+
+    // A return address that overflows can't be the next instruction
+    ret == Some(jump.next() as i64)
+}
+
+/// Get the return address of the call at an address, if there is one there
+///
+/// Only matches a jump that will always be taken, to an immediate address,
+/// at the end of a call sequence.
+pub(crate) fn call_return(memory: &[i64], address: usize) -> Option<usize> {
+    let jump = Instruction::decode(memory, address).ok()?;
+
+    let jumps = match jump.opcode {
+        Opcode::JumpIfTrue(ParameterMode::Immediate, ParameterMode::Immediate) => {
+            jump.parameters[0] != 0
+        }
+        Opcode::JumpIfFalse(ParameterMode::Immediate, ParameterMode::Immediate) => {
+            jump.parameters[0] == 0
+        }
+        _ => false,
+    };
+
+    if jumps && is_call(memory, &jump) {
+        Some(jump.next())
+    } else {
+        None
+    }
+}
+
+/// Decode every instruction reachable from address 0
+///
+/// Returns the instructions (or decoding errors) by address, and the
+/// addresses that start basic blocks.
+fn explore(
+    memory: &[i64],
+    written: &BTreeSet<usize>,
+    fold_conditions: bool,
+) -> (
+    BTreeMap<usize, Result<Instruction, ExecutionError>>,
+    BTreeSet<usize>,
+) {
+    let mut instructions = BTreeMap::new();
+    let mut leaders = BTreeSet::new();
+    let mut pending = vec![0];
+
+    leaders.insert(0);
+
+    while let Some(address) = pending.pop() {
+        if instructions.contains_key(&address) {
+            continue;
+        }
+
+        let instruction = Instruction::decode(memory, address);
+
+        if let Ok(instruction) = &instruction {
+            let exits = successors(memory, instruction, written, fold_conditions);
+
+            for exit in exits.iter() {
+                match exit {
+                    Exit::Next(next) | Exit::Jump(next) | Exit::Call(next) => {
+                        if is_block_end(instruction) {
+                            leaders.insert(*next);
+                        }
+
+                        pending.push(*next);
+                    }
+                    Exit::Indirect | Exit::Invalid(_) | Exit::Return => {}
+                }
+            }
+        }
+
+        instructions.insert(address, instruction);
+    }
+
+    (instructions, leaders)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn straight_line() {
+        let cfg = Cfg::new(&[1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50]);
+
+        assert_eq!(cfg.blocks().len(), 1);
+        let block = cfg.block(0).unwrap();
+        assert_eq!((block.start(), block.end()), (0, 9));
+        assert_eq!(block.exits, vec![]);
+        assert_eq!(cfg.data().collect::<Vec<_>>(), vec![9, 10, 11]);
+    }
+
+    #[test]
+    fn branches() {
+        // From day 5: outputs 999, 1000, or 1001 based on comparing input with 8
+        let memory = [
+            3, 21, 1008, 21, 8, 20, 1005, 20, 22, 107, 8, 21, 20, 1006, 20, 31, 1106, 0, 36, 98, 0,
+            0, 1002, 21, 125, 20, 4, 20, 1105, 1, 46, 104, 999, 1105, 1, 46, 1101, 1000, 1, 20, 4,
+            20, 1105, 1, 46, 98, 99,
+        ];
+        let cfg = Cfg::new(&memory);
+
+        let starts: Vec<_> = cfg.blocks().keys().copied().collect();
+        assert_eq!(starts, vec![0, 9, 16, 22, 31, 36, 46]);
+
+        assert_eq!(
+            cfg.block(0).unwrap().exits,
+            vec![Exit::Jump(22), Exit::Next(9)]
+        );
+        assert_eq!(
+            cfg.block(9).unwrap().exits,
+            vec![Exit::Jump(31), Exit::Next(16)]
+        );
+        // Unconditional jumps don't fall through
+        assert_eq!(cfg.block(16).unwrap().exits, vec![Exit::Jump(36)]);
+        assert_eq!(cfg.block(22).unwrap().exits, vec![Exit::Jump(46)]);
+        assert!(cfg.invalid().is_empty());
+
+        // The 98 and 0 cells between the code are never executed
+        assert!(!cfg.is_code(19));
+        assert!(!cfg.is_code(45));
+        assert!(cfg.is_code(46));
+    }
+
+    #[test]
+    fn self_modified_condition() {
+        // From day 5: the condition of the jump at 2 is overwritten by the input
+        let cfg = Cfg::new(&[3, 3, 1105, -1, 9, 1101, 0, 0, 12, 4, 12, 99, 1]);
+
+        assert_eq!(
+            cfg.block(0).unwrap().exits,
+            vec![Exit::Jump(9), Exit::Next(5)]
+        );
+    }
+
+    #[test]
+    fn indirect_and_invalid() {
+        let cfg = Cfg::new(&[
+            3, 14, 6, 14, 16, 1105, 0, 13, 1105, 1, -5, 99, 0, 0, 0, 0, 11,
+        ]);
+
+        assert_eq!(
+            cfg.block(0).unwrap().exits,
+            vec![Exit::Indirect, Exit::Next(5)]
+        );
+        assert_eq!(cfg.block(5).unwrap().exits, vec![Exit::Next(8)]);
+        assert_eq!(cfg.block(8).unwrap().exits, vec![Exit::Invalid(-5)]);
+
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("    b0 -> indirect [label=\"jump\", style=dashed];\n"));
+        assert!(dot.contains("    b0 -> b5;\n"));
+        assert!(dot.contains("    b8 -> \"invalid -5\" [label=\"jump\"];\n"));
+
+        let cfg = Cfg::new(&[1105, 1, 3, 77]);
+
+        assert_eq!(cfg.block(0).unwrap().exits, vec![Exit::Jump(3)]);
+        assert_eq!(
+            cfg.invalid().get(&3),
+            Some(&ExecutionError::UnknownOpcode(77))
+        );
+
+        let dot = cfg.to_dot();
+        assert!(dot.contains("    b0 -> invalid3 [label=\"jump\"];\n"));
+        assert!(dot.contains("    invalid3 [label=\"3: UnknownOpcode(77)\", color=red];\n"));
+    }
+
+    #[test]
+    fn calls() {
+        let mut memory = vec![
+            109, 100, // Set up the stack
+            21101, 7, 0, 1, // Argument
+            21101, 13, 0, 0, // Return address
+            1105, 1, 16, // Call
+            4, 50, 99, // Output the result
+            1202, 1, 2, 50, // Double the argument
+            2105, 1, 0, // Return
+        ];
+        memory.resize(102, 0);
+        let cfg = Cfg::new(&memory);
+
+        assert_eq!(
+            cfg.block(0).unwrap().exits,
+            vec![Exit::Call(16), Exit::Next(13)]
+        );
+        assert_eq!(cfg.block(13).unwrap().exits, vec![]);
+        assert_eq!(cfg.block(16).unwrap().exits, vec![Exit::Return]);
+
+        let dot = cfg.to_dot();
+        assert!(dot.contains("    b0 -> b16 [label=\"call\", style=bold];\n"));
+        assert!(dot.contains("    b16 -> return [label=\"return\", style=dashed];\n"));
+
+        let mut vm = crate::IntcodeVM::new(memory);
+        vm.run_to_end().unwrap();
+        assert_eq!(vm.pop_output(), Some(14));
+    }
+
+    #[test]
+    fn overflowing_call() {
+        // Looks like a call, but the return address overflows
+        let memory = [21101, i64::MAX, 1, 0, 1105, 1, 7, 99];
+        let cfg = Cfg::new(&memory);
+
+        assert_eq!(cfg.block(0).unwrap().exits, vec![Exit::Jump(7)]);
+        assert_eq!(crate::validate::validate(&memory), vec![]);
+    }
+
+    #[test]
+    fn listing() {
+        let memory = [1105, 1, 4, 42, 104, 7, 99];
+        let cfg = Cfg::new(&memory);
+
+        assert_eq!(
+            cfg.listing(&memory),
+            "     0: jt 1, 4\n     3: data 42\n     4: out 7\n     6: halt\n"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{ParameterMode, Word};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The stages of the machine, as the puzzles added to it
+///
+/// Defaults to the complete machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IsaLevel {
+    /// Add, multiply and halt, with position parameters only
+    Day2,
+    /// Adds input, output, jumps and comparisons, and immediate parameters
+    Day5,
+    /// Adds relative base adjustment and relative parameters
+    #[default]
+    Day9,
+}
+
+impl IsaLevel {
+    /// Check if a built-in opcode is available at this level
+    pub fn allows_opcode(self, opcode: i64) -> bool {
+        match opcode {
+            1 | 2 | 99 => true,
+            3..=8 => self >= IsaLevel::Day5,
+            9 => self >= IsaLevel::Day9,
+            _ => false,
+        }
+    }
+
+    /// Check if a parameter mode is available at this level
+    pub fn allows_mode(self, mode: ParameterMode) -> bool {
+        match mode {
+            ParameterMode::Position => true,
+            ParameterMode::Immediate => self >= IsaLevel::Day5,
+            ParameterMode::Relative => self >= IsaLevel::Day9,
+        }
+    }
+}
+
+/// What happens when `Add`, `Multiply` or adjusting the relative base overflows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Arithmetic {
+    /// Fail with `ExecutionError::Overflow`
+    #[default]
+    Checked,
+    /// Wrap around, as two's complement
+    Wrapping,
+    /// Stop at the largest or smallest value
+    Saturating,
+}
+
+impl Arithmetic {
+    /// Add two values, or return `None` if checked addition overflows
+    pub fn add<W: Word>(self, a: W, b: W) -> Option<W> {
+        match self {
+            Arithmetic::Checked => a.checked_add(b),
+            Arithmetic::Wrapping => Some(a.wrapping_add(b)),
+            Arithmetic::Saturating => Some(a.saturating_add(b)),
+        }
+    }
+
+    /// Multiply two values, or return `None` if checked multiplication overflows
+    pub fn multiply<W: Word>(self, a: W, b: W) -> Option<W> {
+        match self {
+            Arithmetic::Checked => a.checked_mul(b),
+            Arithmetic::Wrapping => Some(a.wrapping_mul(b)),
+            Arithmetic::Saturating => Some(a.saturating_mul(b)),
+        }
+    }
+}
+
+/// Resource limits, for running untrusted or generated programs
+///
+/// Limits are checked before each instruction runs, and a VM that goes past
+/// one fails with its own error: `OutputLimit`, `StepLimit` or
+/// `DeadlineExceeded`. The instruction that would have gone past the limit
+/// isn't run, so the VM is left as it was for inspection. Memory never grows,
+/// so its limit is checked once instead, by `Machine::with_config`, which
+/// fails with `MemoryLimit`. `None` means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// The most memory cells the VM can be created with
+    pub memory: Option<usize>,
+    /// The most outputs that can wait in the output queue
+    pub outputs: Option<usize>,
+    /// The most instructions the VM can run
+    pub steps: Option<u64>,
+    /// When the VM has to stop running
+    pub deadline: Option<Instant>,
+}
+
+/// A flag for stopping a VM from another thread
+///
+/// Once cancelled, a VM using the token fails every step with `Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Create a token that hasn't been cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop every VM using this token
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Check if the token has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Settings for how an `IntcodeVM` runs
+///
+/// The default is the complete machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VmConfig {
+    /// The stage of the machine to run as
+    ///
+    /// Built-in opcodes and parameter modes from later stages fail with
+    /// `UnsupportedOpcode` and `UnsupportedMode`. Opcodes added with an
+    /// `isa::InstructionSet` aren't affected, but their modes are.
+    pub level: IsaLevel,
+    /// How to handle arithmetic overflow
+    pub arithmetic: Arithmetic,
+    /// Resource limits, with none by default
+    pub limits: Limits,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ExecutionError, IntcodeVM};
+
+    fn run(level: IsaLevel, program: &[i64], input: &[i64]) -> Result<Vec<i64>, ExecutionError> {
+        let config = VmConfig {
+            level,
+            ..VmConfig::default()
+        };
+        let mut vm = IntcodeVM::with_config(program.to_vec(), config)?;
+        vm.push_inputs(input.iter().copied());
+        vm.run_to_end()?;
+        Ok(vm.iter_output().copied().collect())
+    }
+
+    const DAY2: &[i64] = &[1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50];
+    const DAY5: &[i64] = &[3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8];
+    const DAY9: &[i64] = &[109, 5, 204, -5, 99, 0];
+
+    #[test]
+    fn day2() {
+        let mut vm = IntcodeVM::with_config(
+            DAY2.to_vec(),
+            VmConfig {
+                level: IsaLevel::Day2,
+                ..VmConfig::default()
+            },
+        )
+        .unwrap();
+        vm.run_to_end().unwrap();
+        assert_eq!(vm.get_memory(0), Ok(3500));
+
+        assert_eq!(
+            run(IsaLevel::Day2, DAY5, &[8]),
+            Err(ExecutionError::UnsupportedOpcode(3))
+        );
+        assert_eq!(
+            run(IsaLevel::Day2, &[1101, 1, 1, 0, 99], &[]),
+            Err(ExecutionError::UnsupportedMode(1))
+        );
+    }
+
+    #[test]
+    fn day5() {
+        assert_eq!(run(IsaLevel::Day5, DAY2, &[]), Ok(vec![]));
+        assert_eq!(run(IsaLevel::Day5, DAY5, &[8]), Ok(vec![1]));
+        assert_eq!(run(IsaLevel::Day5, DAY5, &[7]), Ok(vec![0]));
+        assert_eq!(run(IsaLevel::Day5, &[1105, 1, 3, 99], &[]), Ok(vec![]));
+
+        assert_eq!(
+            run(IsaLevel::Day5, DAY9, &[]),
+            Err(ExecutionError::UnsupportedOpcode(9))
+        );
+        assert_eq!(
+            run(IsaLevel::Day5, &[204, 0, 99], &[]),
+            Err(ExecutionError::UnsupportedMode(2))
+        );
+    }
+
+    #[test]
+    fn day9() {
+        assert_eq!(run(IsaLevel::Day9, DAY2, &[]), Ok(vec![]));
+        assert_eq!(run(IsaLevel::Day9, DAY5, &[8]), Ok(vec![1]));
+        assert_eq!(run(IsaLevel::Day9, DAY9, &[]), Ok(vec![109]));
+        assert_eq!(IntcodeVM::new(DAY9.to_vec()).config(), &VmConfig::default());
+    }
+
+    fn overflow(arithmetic: Arithmetic, program: &[i64]) -> Result<i64, ExecutionError> {
+        let config = VmConfig {
+            arithmetic,
+            ..VmConfig::default()
+        };
+        let mut vm = IntcodeVM::with_config(program.to_vec(), config)?;
+        vm.run_to_end()?;
+        vm.get_memory(0)
+    }
+
+    #[test]
+    fn arithmetic() {
+        let max = i64::MAX;
+        let add = [104, 0, 1101, max, 1, 0, 99];
+        let multiply = [104, 0, 1102, max, -2, 0, 99];
+
+        assert_eq!(
+            overflow(Arithmetic::Checked, &add),
+            Err(ExecutionError::Overflow { pc: 2 })
+        );
+        assert_eq!(overflow(Arithmetic::Wrapping, &add), Ok(i64::MIN));
+        assert_eq!(overflow(Arithmetic::Saturating, &add), Ok(max));
+
+        assert_eq!(
+            overflow(Arithmetic::Checked, &multiply),
+            Err(ExecutionError::Overflow { pc: 2 })
+        );
+        assert_eq!(overflow(Arithmetic::Wrapping, &multiply), Ok(2));
+        assert_eq!(overflow(Arithmetic::Saturating, &multiply), Ok(i64::MIN));
+
+        assert_eq!(
+            overflow(Arithmetic::Checked, &[109, max, 109, 1, 99]),
+            Err(ExecutionError::Overflow { pc: 2 })
+        );
+        assert_eq!(overflow(Arithmetic::Checked, &[1101, 2, 3, 0, 99]), Ok(5));
+    }
+
+    fn limited(limits: Limits, program: &[i64]) -> (IntcodeVM, Result<(), ExecutionError>) {
+        let config = VmConfig {
+            limits,
+            ..VmConfig::default()
+        };
+        let mut vm = IntcodeVM::with_config(program.to_vec(), config).unwrap();
+        let result = vm.run_to_end();
+        (vm, result)
+    }
+
+    // Output 1, 2, 3, ... forever, keeping the count in the jump condition
+    const COUNT: &[i64] = &[1001, 7, 1, 7, 4, 7, 1105, 0, 0];
+
+    #[test]
+    fn limits() {
+        let (vm, result) = limited(
+            Limits {
+                steps: Some(10),
+                ..Limits::default()
+            },
+            COUNT,
+        );
+        assert_eq!(result, Err(ExecutionError::StepLimit));
+        assert_eq!(vm.steps(), 10);
+        assert_eq!(vm.pc(), 4);
+
+        let (vm, result) = limited(
+            Limits {
+                outputs: Some(3),
+                ..Limits::default()
+            },
+            COUNT,
+        );
+        assert_eq!(result, Err(ExecutionError::OutputLimit));
+        assert_eq!(vm.iter_output().copied().collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(vm.pc(), 4);
+        assert_eq!(vm.get_memory(7), Ok(4));
+
+        let config = VmConfig {
+            limits: Limits {
+                memory: Some(8),
+                ..Limits::default()
+            },
+            ..VmConfig::default()
+        };
+        assert_eq!(
+            IntcodeVM::with_config(COUNT.to_vec(), config).unwrap_err(),
+            ExecutionError::MemoryLimit
+        );
+
+        let (_, result) = limited(
+            Limits {
+                deadline: Some(Instant::now()),
+                ..Limits::default()
+            },
+            COUNT,
+        );
+        assert_eq!(result, Err(ExecutionError::DeadlineExceeded));
+
+        let (_, result) = limited(
+            Limits {
+                memory: Some(9),
+                outputs: Some(1),
+                steps: Some(10),
+                deadline: Some(Instant::now() + std::time::Duration::from_secs(60)),
+            },
+            &[104, 1, 99],
+        );
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut vm = IntcodeVM::new(vec![1105, 1, 0]);
+        let token = vm.cancel_token();
+
+        let handle = std::thread::spawn(move || {
+            let result = vm.run_to_end();
+            (vm, result)
+        });
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        token.cancel();
+
+        let (vm, result) = handle.join().unwrap();
+        assert_eq!(result, Err(ExecutionError::Cancelled));
+        assert!(vm.steps() > 0);
+        assert_eq!(vm.pc(), 0);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::cfg::Cfg;
+use crate::disasm::Instruction;
+use crate::{IntcodeVM, Opcode, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// How often each instruction and branch direction of a program was run
+///
+/// Collect coverage by running VMs loaded with the program through `step` or
+/// `run_to_end`, as many times as needed; counts from every run add up.
+/// Coverage collected separately, such as from parallel runs, can be combined
+/// with `merge`.
+///
+/// Reports are laid out over the reachable code found by `cfg::Cfg`, so code
+/// that's only created at runtime by self-modification isn't shown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    image: Vec<i64>,
+    executions: BTreeMap<usize, u64>,
+    /// How often each conditional jump was `(taken, not taken)`
+    branches: BTreeMap<usize, (u64, u64)>,
+}
+
+impl Coverage {
+    /// Start collecting coverage for a program image
+    pub fn new(image: &[i64]) -> Self {
+        Self {
+            image: image.to_vec(),
+            executions: BTreeMap::new(),
+            branches: BTreeMap::new(),
+        }
+    }
+
+    /// Get the number of times the instruction at an address was executed
+    pub fn executions(&self, address: usize) -> u64 {
+        self.executions.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Get how often the jump at an address was taken and not taken
+    pub fn branch(&self, address: usize) -> Option<(u64, u64)> {
+        self.branches.get(&address).copied()
+    }
+
+    /// Add the counts from other coverage of the same program
+    pub fn merge(&mut self, other: &Coverage) {
+        for (address, count) in other.executions.iter() {
+            *self.executions.entry(*address).or_insert(0) += count;
+        }
+
+        for (address, (taken, not_taken)) in other.branches.iter() {
+            let branch = self.branches.entry(*address).or_insert((0, 0));
+            branch.0 += taken;
+            branch.1 += not_taken;
+        }
+    }
+
+    /// Take a single step through a program, recording what ran
+    ///
+    /// Behaves exactly like `IntcodeVM::step`.
+    pub fn step(&mut self, vm: &mut IntcodeVM) -> Result<bool> {
+        if vm.halted() {
+            return vm.step();
+        }
+
+        let pc = vm.pc();
+        let instruction = Instruction::decode(vm.memory(), pc)?;
+        let running = vm.step()?;
+
+        *self.executions.entry(pc).or_insert(0) += 1;
+
+        if let Opcode::JumpIfTrue(..) | Opcode::JumpIfFalse(..) = instruction.opcode {
+            let branch = self.branches.entry(pc).or_insert((0, 0));
+
+            if vm.pc() != instruction.next() {
+                branch.0 += 1;
+            } else {
+                branch.1 += 1;
+            }
+        }
+
+        Ok(running)
+    }
+
+    /// Run a program until it halts, recording what ran
+    pub fn run_to_end(&mut self, vm: &mut IntcodeVM) -> Result<()> {
+        while self.step(vm)? {}
+
+        Ok(())
+    }
+
+    /// Write the disassembly of the program with execution counts
+    ///
+    /// Each instruction is prefixed with its count, or `#####` if it never ran,
+    /// and each conditional jump is followed by how often it went each way.
+    pub fn annotated(&self) -> String {
+        let mut out = String::new();
+
+        for (address, instruction) in self.lines() {
+            let instruction = match instruction {
+                Some(instruction) => instruction,
+                None => {
+                    let value = self.image[address];
+                    writeln!(out, "{:>8}  {:>6}: data {}", "", address, value).unwrap();
+                    continue;
+                }
+            };
+
+            let count = match self.executions(address) {
+                0 => "#####".to_string(),
+                count => count.to_string(),
+            };
+            write!(out, "{:>8}  {:>6}: {}", count, address, instruction).unwrap();
+
+            if let Some((taken, not_taken)) = self.branch_counts(&instruction) {
+                write!(out, "  [taken {}, not taken {}]", taken, not_taken).unwrap();
+            }
+
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// Write the coverage in lcov's tracefile format
+    ///
+    /// Line numbers refer to `cfg::Cfg::listing` of the image, so save that
+    /// listing as the file `source` to view the report with lcov's tools.
+    pub fn lcov(&self, source: &str) -> String {
+        let mut out = String::new();
+        let mut lines = (0, 0);
+        let mut branches = (0, 0);
+        let mut branch_lines = String::new();
+
+        writeln!(out, "TN:\nSF:{}", source).unwrap();
+
+        for (line, (address, instruction)) in self.lines().into_iter().enumerate() {
+            let line = line + 1;
+            let instruction = match instruction {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+
+            let count = self.executions(address);
+            writeln!(out, "DA:{},{}", line, count).unwrap();
+            lines.0 += 1;
+            lines.1 += (count > 0) as usize;
+
+            if let Some((taken, not_taken)) = self.branch_counts(&instruction) {
+                for (direction, hits) in [taken, not_taken].iter().enumerate() {
+                    let hits = if count > 0 {
+                        hits.to_string()
+                    } else {
+                        "-".to_string()
+                    };
+                    writeln!(branch_lines, "BRDA:{},0,{},{}", line, direction, hits).unwrap();
+                }
+
+                branches.0 += 2;
+                branches.1 += (taken > 0) as usize + (not_taken > 0) as usize;
+            }
+        }
+
+        out += &branch_lines;
+        writeln!(out, "BRF:{}\nBRH:{}", branches.0, branches.1).unwrap();
+        writeln!(out, "LF:{}\nLH:{}", lines.0, lines.1).unwrap();
+        out += "end_of_record\n";
+
+        out
+    }
+
+    /// Get each line of `cfg::Cfg::listing`, with the instruction if it's code
+    fn lines(&self) -> Vec<(usize, Option<Instruction>)> {
+        Cfg::new(&self.image).lines(&self.image)
+    }
+
+    /// Get how often a conditional jump went each way, including never
+    fn branch_counts(&self, instruction: &Instruction) -> Option<(u64, u64)> {
+        match instruction.opcode {
+            Opcode::JumpIfTrue(..) | Opcode::JumpIfFalse(..) => {
+                Some(self.branch(instruction.address).unwrap_or((0, 0)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Output 0 if the input is 0, otherwise 1
+    const PROGRAM: &[i64] = &[3, 12, 6, 12, 15, 1, 13, 14, 13, 4, 13, 99, -1, 0, 1, 9];
+
+    #[test]
+    fn one_run() {
+        let mut coverage = Coverage::new(PROGRAM);
+        let mut vm = IntcodeVM::new(PROGRAM.to_vec());
+        vm.push_input(0);
+        coverage.run_to_end(&mut vm).unwrap();
+
+        assert_eq!(coverage.executions(0), 1);
+        assert_eq!(coverage.executions(5), 0);
+        assert_eq!(coverage.branch(2), Some((1, 0)));
+
+        let annotated = coverage.annotated();
+        assert!(annotated.starts_with(
+            "       1       0: in [12]\n       \
+                    1       2: jf [12], [15]  [taken 1, not taken 0]\n   \
+                #####       5: add [13], [14], [13]\n"
+        ));
+        assert!(annotated.ends_with("              15: data 9\n"));
+
+        assert_eq!(
+            coverage.lcov("program.txt"),
+            "TN:\n\
+             SF:program.txt\n\
+             DA:1,1\n\
+             DA:2,1\n\
+             DA:3,0\n\
+             DA:4,1\n\
+             DA:5,1\n\
+             BRDA:2,0,0,1\n\
+             BRDA:2,0,1,0\n\
+             BRF:2\n\
+             BRH:1\n\
+             LF:5\n\
+             LH:4\n\
+             end_of_record\n"
+        );
+    }
+
+    #[test]
+    fn lines_match_listing() {
+        let coverage = Coverage::new(PROGRAM);
+        let listing = Cfg::new(PROGRAM).listing(PROGRAM);
+        let listing: Vec<_> = listing.lines().collect();
+
+        for line in coverage.lcov("program.txt").lines() {
+            if let Some(line) = line.strip_prefix("DA:") {
+                let number: usize = line.split(',').next().unwrap().parse().unwrap();
+                assert!(!listing[number - 1].contains("data"));
+            }
+        }
+
+        let annotated: Vec<_> = coverage
+            .annotated()
+            .lines()
+            .map(|l| l[10..].to_string())
+            .collect();
+        assert_eq!(annotated.len(), listing.len());
+        for (annotated, listing) in annotated.iter().zip(listing.iter()) {
+            assert!(annotated.starts_with(listing));
+        }
+    }
+
+    #[test]
+    fn merged_runs() {
+        let mut coverage = Coverage::new(PROGRAM);
+
+        for input in 0..3 {
+            let mut run = Coverage::new(PROGRAM);
+            let mut vm = IntcodeVM::new(PROGRAM.to_vec());
+            vm.push_input(input);
+            run.run_to_end(&mut vm).unwrap();
+            coverage.merge(&run);
+        }
+
+        assert_eq!(coverage.executions(0), 3);
+        assert_eq!(coverage.executions(5), 2);
+        assert_eq!(coverage.branch(2), Some((1, 2)));
+        assert!(coverage.lcov("program.txt").contains("BRH:2\nLF:5\nLH:5\n"));
+    }
+}
@@ -0,0 +1,309 @@
+use crate::{ExecutionError, IntcodeVM, Opcode};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// An error from a program run with cycle detection
+#[derive(Debug, Clone, PartialEq)]
+pub enum CycleError {
+    /// The program failed in the same way a plain VM would
+    Execution(ExecutionError),
+    /// The program is stuck in a loop without any input or output
+    ///
+    /// `start` and `end` are the addresses of the first and last instructions
+    /// executed in the loop.
+    InfiniteLoop { start: usize, end: usize },
+    /// The VM has devices mapped, whose state can't be compared, so loops
+    /// can't be told apart from polling
+    DevicesMapped,
+}
+
+impl From<ExecutionError> for CycleError {
+    fn from(error: ExecutionError) -> Self {
+        CycleError::Execution(error)
+    }
+}
+
+type Result<T> = std::result::Result<T, CycleError>;
+
+/// A VM that stops programs stuck in an infinite loop instead of hanging
+///
+/// The full state of the VM (PC, memory, relative base and queue lengths) is
+/// compared after backward jumps. Since the VM is deterministic, seeing the
+/// same state twice with no input or output in between means the program will
+/// go round forever. Any input or output forgets the state seen so far.
+///
+/// Only one earlier state is kept, using Brent's algorithm: it's replaced
+/// after 1, 2, 4, 8, ... backward jumps, so any loop is found within about
+/// twice its length. Memory is only hashed when a state is kept, or when the
+/// rest of the state matches the kept one.
+///
+/// A repeated hash is confirmed by running one more time around the loop on a
+/// detached copy of the VM, so hash collisions can't cause false alarms.
+///
+/// A VM with devices mapped fails with `DevicesMapped`, since a loop reading
+/// a device may be waiting for it to change.
+#[derive(Debug, Clone)]
+pub struct CycleDetector {
+    vm: IntcodeVM,
+    /// The state kept for comparison, and the step count when it was kept
+    kept: Option<(State, usize)>,
+    /// Backward jumps since the state was kept, and how many until it's replaced
+    jumps: usize,
+    limit: usize,
+    steps: usize,
+}
+
+/// The state of a VM, with memory boiled down to a hash
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    pc: usize,
+    relative_base: i64,
+    queues: (usize, usize),
+    memory: u64,
+}
+
+impl CycleDetector {
+    /// Start watching a VM
+    pub fn new(vm: IntcodeVM) -> Self {
+        Self {
+            vm,
+            kept: None,
+            jumps: 0,
+            limit: 1,
+            steps: 0,
+        }
+    }
+
+    /// Get the VM being watched
+    pub fn vm(&self) -> &IntcodeVM {
+        &self.vm
+    }
+
+    /// Get the VM being watched, to add input or take output
+    ///
+    /// Changing its memory directly may hide or fake a loop.
+    pub fn vm_mut(&mut self) -> &mut IntcodeVM {
+        &mut self.vm
+    }
+
+    /// Stop watching the VM and get it back
+    pub fn into_inner(self) -> IntcodeVM {
+        self.vm
+    }
+
+    /// Take a single step through the program
+    ///
+    /// Behaves like `IntcodeVM::step`, but fails with `InfiniteLoop` if the
+    /// step completes a loop that can never be left.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.vm.halted() {
+            return Ok(self.vm.step()?);
+        }
+
+        if !self.vm.devices.is_empty() {
+            return Err(CycleError::DevicesMapped);
+        }
+
+        let pc = self.vm.pc();
+        let opcode = Opcode::from_raw(self.vm.current_raw_opcode()?)?;
+        let running = self.vm.step()?;
+        self.steps += 1;
+
+        match opcode {
+            Opcode::Input(..) | Opcode::Output(..) => self.forget(),
+            Opcode::JumpIfTrue(..) | Opcode::JumpIfFalse(..) if self.vm.pc() <= pc => {
+                self.backward_jump()?
+            }
+            _ => {}
+        }
+
+        Ok(running)
+    }
+
+    /// Run the program until it halts
+    pub fn run_to_end(&mut self) -> Result<()> {
+        while self.step()? {}
+
+        Ok(())
+    }
+
+    fn forget(&mut self) {
+        self.kept = None;
+        self.jumps = 0;
+        self.limit = 1;
+    }
+
+    fn backward_jump(&mut self) -> Result<()> {
+        if let Some((kept, kept_at)) = &self.kept {
+            let (pc, relative_base, queues) = cheap_state(&self.vm);
+
+            // Only hash memory if everything else already matches
+            if (pc, relative_base, queues) == (kept.pc, kept.relative_base, kept.queues)
+                && memory_hash(&self.vm) == kept.memory
+            {
+                if let Some((start, end)) = loop_range(&self.vm, self.steps - kept_at, kept) {
+                    return Err(CycleError::InfiniteLoop { start, end });
+                }
+            }
+        }
+
+        self.jumps += 1;
+
+        if self.kept.is_none() || self.jumps == self.limit {
+            self.kept = Some((state(&self.vm), self.steps));
+            self.jumps = 0;
+            self.limit *= 2;
+        }
+
+        Ok(())
+    }
+}
+
+fn cheap_state(vm: &IntcodeVM) -> (usize, i64, (usize, usize)) {
+    (vm.pc, vm.relative_base, (vm.input.len(), vm.output.len()))
+}
+
+fn memory_hash(vm: &IntcodeVM) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    vm.memory.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn state(vm: &IntcodeVM) -> State {
+    let (pc, relative_base, queues) = cheap_state(vm);
+
+    State {
+        pc,
+        relative_base,
+        queues,
+        memory: memory_hash(vm),
+    }
+}
+
+/// Run a detached copy of the VM for one period and find the range of PCs it visits
+///
+/// Returns `None` if it doesn't come back to the same state, which means the
+/// repeated hash was a collision.
+fn loop_range(vm: &IntcodeVM, period: usize, kept: &State) -> Option<(usize, usize)> {
+    let mut vm = vm.detached();
+    let mut start = vm.pc();
+    let mut end = vm.pc();
+
+    for _ in 0..period {
+        start = start.min(vm.pc());
+        end = end.max(vm.pc());
+        vm.step().ok()?;
+    }
+
+    if state(&vm) == *kept {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn jump_to_self() {
+        let mut detector = CycleDetector::new(IntcodeVM::new(vec![1105, 1, 0]));
+
+        assert_eq!(
+            detector.run_to_end(),
+            Err(CycleError::InfiniteLoop { start: 0, end: 0 })
+        );
+    }
+
+    #[test]
+    fn long_period() {
+        #[derive(Default)]
+        struct Steps(u64);
+
+        impl crate::observer::Observer for Steps {
+            fn on_step(&mut self, _pc: usize, _vm: &IntcodeVM) {
+                self.0 += 1;
+            }
+        }
+
+        // Count 0 to 4 round and round, which takes five backward jumps
+        let mut memory = vec![
+            1001, 30, 1, 30, // Count up
+            1008, 30, 5, 31, // Check for 5
+            1006, 31, 0, // Go round again until then
+            1101, 0, 0, 30, // Reset the count
+            1105, 1, 0, // And go round again
+        ];
+        memory.resize(32, 0);
+
+        let mut vm = IntcodeVM::new(memory);
+        let steps = vm.observe(Steps::default());
+        let mut detector = CycleDetector::new(vm);
+
+        assert_eq!(
+            detector.run_to_end(),
+            Err(CycleError::InfiniteLoop { start: 0, end: 15 })
+        );
+
+        // Confirming the loop doesn't show up as steps of the real VM
+        assert_eq!(steps.lock().unwrap().0, detector.vm().steps());
+    }
+
+    #[test]
+    fn devices() {
+        // Poll a clock until it reaches 5
+        let mut vm = IntcodeVM::new(vec![1008, 20, 5, 21, 1006, 21, 0, 99]);
+        vm.map_device(20..21, crate::device::Clock::default());
+
+        let mut detector = CycleDetector::new(vm);
+        assert_eq!(detector.run_to_end(), Err(CycleError::DevicesMapped));
+    }
+
+    #[test]
+    fn finite_loop_then_infinite() {
+        let mut memory = vec![
+            1001, 20, -1, 20, // Count down
+            1005, 20, 0, // Until zero
+            104, 7, // Output
+            1101, 0, 0, 21, // Reset a flag that never changes
+            1006, 21, 9, // Loop while the flag is unset
+            99,
+        ];
+        memory.resize(22, 0);
+        memory[20] = 3;
+
+        let mut detector = CycleDetector::new(IntcodeVM::new(memory));
+
+        assert_eq!(
+            detector.run_to_end(),
+            Err(CycleError::InfiniteLoop { start: 9, end: 13 })
+        );
+        assert_eq!(detector.vm_mut().pop_output(), Some(7));
+        assert_eq!(detector.vm().get_memory(20), Ok(0));
+    }
+
+    #[test]
+    fn matches_vm() {
+        // Echo inputs until a zero, so each pass only differs in its I/O
+        let program = vec![3, 9, 4, 9, 1005, 9, 0, 99, 0, 0];
+
+        let mut detector = CycleDetector::new(IntcodeVM::new(program));
+        detector.vm_mut().push_inputs(vec![5, 5, 5]);
+        assert_eq!(
+            detector.run_to_end(),
+            Err(CycleError::Execution(ExecutionError::NeedsInput))
+        );
+
+        detector.vm_mut().push_input(0);
+        assert_eq!(detector.run_to_end(), Ok(()));
+        assert_eq!(
+            detector
+                .into_inner()
+                .iter_output()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![5, 5, 5, 0]
+        );
+    }
+}
@@ -0,0 +1,500 @@
+use crate::cfg::{Cfg, Exit};
+use crate::disasm::Instruction;
+use crate::{Opcode, ParameterMode};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Decompile a program image into structured pseudocode
+///
+/// The program is split into functions at every call found by `cfg::Cfg`,
+/// with address 0 as `main`. Within a function, backward jumps become `while`,
+/// `loop` and `do ... while` loops, forward conditional jumps become `if` and
+/// `else`, and anything that doesn't fit falls back to `goto`.
+///
+/// Memory cells are named `vN` after their address and declared up front with
+/// their initial values; relative-base slots are `rb[N]`. Call setup is folded
+/// into the `call`, so arguments show up as the `rb` slots written before it.
+pub fn decompile(memory: &[i64]) -> String {
+    let cfg = Cfg::new(memory);
+
+    let exits: BTreeMap<usize, &[Exit]> = cfg
+        .blocks()
+        .values()
+        .map(|block| (block.instructions.last().unwrap().address, &block.exits[..]))
+        .collect();
+
+    let mut entries = vec![0];
+    entries.extend(
+        cfg.blocks()
+            .values()
+            .flat_map(|block| block.exits.iter())
+            .filter_map(|exit| match exit {
+                Exit::Call(target) => Some(*target),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|&target| target != 0),
+    );
+
+    let functions: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| cfg.block(*entry).is_some())
+        .map(|entry| (entry, function_body(&cfg, entry)))
+        .collect();
+
+    let variables: BTreeSet<usize> = functions
+        .iter()
+        .flat_map(|(_, instructions)| instructions.iter())
+        .flat_map(|instruction| {
+            instruction
+                .opcode
+                .modes()
+                .into_iter()
+                .zip(instruction.parameters.iter().copied())
+        })
+        .filter(|(mode, value)| *mode == ParameterMode::Position && *value >= 0)
+        .map(|(_, value)| value as usize)
+        .collect();
+
+    let mut out = String::new();
+
+    for variable in variables.iter() {
+        let value = memory.get(*variable).copied().unwrap_or(0);
+        writeln!(out, "var v{} = {};", variable, value).unwrap();
+    }
+
+    for (entry, instructions) in functions.iter() {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+
+        let mut writer = Writer {
+            instructions,
+            exits: &exits,
+            lines: Vec::new(),
+            loops: Vec::new(),
+            gotos: BTreeSet::new(),
+        };
+        writer.block(0, instructions.len(), 1);
+
+        writeln!(out, "fn {}() {{", function_name(*entry)).unwrap();
+        out += &writer.finish();
+        out += "}\n";
+    }
+
+    out
+}
+
+/// Get the instructions of the function starting at an entry point, in address order
+///
+/// Calls are stepped over rather than followed, and returns end the function.
+fn function_body(cfg: &Cfg, entry: usize) -> Vec<&Instruction> {
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![entry];
+
+    while let Some(start) = pending.pop() {
+        let block = match cfg.block(start) {
+            Some(block) if seen.insert(start) => block,
+            _ => continue,
+        };
+
+        for exit in block.exits.iter() {
+            if let Exit::Next(next) | Exit::Jump(next) = exit {
+                pending.push(*next);
+            }
+        }
+    }
+
+    seen.into_iter()
+        .flat_map(|start| cfg.block(start).unwrap().instructions.iter())
+        .collect()
+}
+
+fn function_name(entry: usize) -> String {
+    if entry == 0 {
+        "main".to_string()
+    } else {
+        format!("func_{}", entry)
+    }
+}
+
+enum Line {
+    /// A place a `goto` might land, at an instruction's address
+    Label(usize, usize),
+    Statement(usize, String),
+}
+
+/// Structures one function's instructions into lines of pseudocode
+struct Writer<'a> {
+    instructions: &'a [&'a Instruction],
+    exits: &'a BTreeMap<usize, &'a [Exit]>,
+    lines: Vec<Line>,
+    /// The `(header, exit)` addresses of each loop being written, innermost last
+    loops: Vec<(usize, usize)>,
+    gotos: BTreeSet<usize>,
+}
+
+impl<'a> Writer<'a> {
+    /// Write the instructions in `start..end`
+    fn block(&mut self, mut start: usize, end: usize, depth: usize) {
+        while start < end {
+            let address = self.instructions[start].address;
+            self.lines.push(Line::Label(depth, address));
+
+            start = match self.latch(start, end) {
+                Some(latch) => self.looped(start, latch, depth),
+                None => self.statement(start, end, depth),
+            };
+        }
+    }
+
+    /// Write a loop from `header` to `latch`, returning the index after it
+    fn looped(&mut self, header: usize, latch: usize, depth: usize) -> usize {
+        let header_address = self.instructions[header].address;
+        let exit = self.instructions[latch].next();
+
+        self.loops.push((header_address, exit));
+
+        if self.conditional(latch) {
+            self.line(depth, "do {".to_string());
+            self.block(header, latch, depth + 1);
+            let condition = self.condition(latch, false);
+            self.line(depth, format!("}} while ({});", condition));
+        } else if self.conditional(header) && self.exits(header).contains(&Exit::Jump(exit)) {
+            let condition = self.condition(header, true);
+            self.line(depth, format!("while ({}) {{", condition));
+            self.block(header + 1, latch, depth + 1);
+            self.line(depth, "}".to_string());
+        } else {
+            self.line(depth, "loop {".to_string());
+            self.block(header, latch, depth + 1);
+            self.line(depth, "}".to_string());
+        }
+
+        self.loops.pop();
+        latch + 1
+    }
+
+    /// Write a single statement, returning the index of the next one
+    fn statement(&mut self, index: usize, end: usize, depth: usize) -> usize {
+        let instruction = self.instructions[index];
+        let operand = |i: usize| operand(instruction, i);
+
+        let text = match instruction.opcode {
+            Opcode::JumpIfTrue(..) | Opcode::JumpIfFalse(..) => {
+                return self.jump(index, end, depth)
+            }
+            _ if self.is_call_setup(index, end) => return index + 1,
+            Opcode::Add(..) if instruction.parameters[1] == 0 && is_immediate(instruction, 1) => {
+                format!("{} = {};", operand(2), operand(0))
+            }
+            Opcode::Add(..) => format!("{} = {} + {};", operand(2), operand(0), operand(1)),
+            Opcode::Multiply(..)
+                if instruction.parameters[1] == 1 && is_immediate(instruction, 1) =>
+            {
+                format!("{} = {};", operand(2), operand(0))
+            }
+            Opcode::Multiply(..) => {
+                format!("{} = {} * {};", operand(2), operand(0), operand(1))
+            }
+            Opcode::LessThan(..) => format!("{} = {} < {};", operand(2), operand(0), operand(1)),
+            Opcode::Equals(..) => format!("{} = {} == {};", operand(2), operand(0), operand(1)),
+            Opcode::Input(..) => format!("{} = input();", operand(0)),
+            Opcode::Output(..) => format!("output({});", operand(0)),
+            Opcode::AdjustRelativeBase(..) => format!("rb += {};", operand(0)),
+            Opcode::Halt => "halt();".to_string(),
+        };
+
+        self.line(depth, text);
+        index + 1
+    }
+
+    /// Write a jump, returning the index of the next statement
+    fn jump(&mut self, index: usize, end: usize, depth: usize) -> usize {
+        let instruction = self.instructions[index];
+        let conditional = self.conditional(index);
+        let target = self
+            .exits(index)
+            .iter()
+            .find(|exit| !matches!(exit, Exit::Next(_)))
+            .copied();
+
+        let text = match target {
+            // Never jumps
+            None => return index + 1,
+            Some(Exit::Call(target)) => format!("call {}();", function_name(target)),
+            Some(Exit::Return) => "return;".to_string(),
+            Some(Exit::Jump(target)) if conditional && target > instruction.address => {
+                if let Some(next) = self.if_else(index, target, end, depth) {
+                    return next;
+                }
+                format!(
+                    "if ({}) {}",
+                    self.condition(index, false),
+                    self.goto(target)
+                )
+            }
+            Some(Exit::Jump(target)) if conditional => {
+                format!(
+                    "if ({}) {}",
+                    self.condition(index, false),
+                    self.goto(target)
+                )
+            }
+            Some(Exit::Jump(target)) => self.goto(target),
+            Some(_) if conditional => format!(
+                "if ({}) jump({});",
+                self.condition(index, false),
+                operand(instruction, 1)
+            ),
+            Some(_) => format!("jump({});", operand(instruction, 1)),
+        };
+
+        self.line(depth, text);
+        index + 1
+    }
+
+    /// Try to write a forward conditional jump as an `if`, returning the index after it
+    fn if_else(&mut self, index: usize, target: usize, end: usize, depth: usize) -> Option<usize> {
+        if self.loops.last().map(|(_, exit)| *exit) == Some(target) {
+            return None;
+        }
+
+        let then_end = self.find(target, end)?;
+
+        // A then-branch ending in a forward jump past the target skips an else-branch
+        let else_end = match then_end.checked_sub(1).map(|last| (last, self.exits(last))) {
+            Some((last, &[Exit::Jump(skip)])) if last > index && skip > target => {
+                match self.loops.last() {
+                    Some((header, exit)) if skip == *header || skip == *exit => None,
+                    _ => self.find(skip, end),
+                }
+            }
+            _ => None,
+        };
+
+        let condition = self.condition(index, true);
+        self.line(depth, format!("if ({}) {{", condition));
+
+        match else_end {
+            Some(else_end) => {
+                self.block(index + 1, then_end - 1, depth + 1);
+                self.line(depth, "} else {".to_string());
+                self.block(then_end, else_end, depth + 1);
+                self.line(depth, "}".to_string());
+                Some(else_end)
+            }
+            None => {
+                self.block(index + 1, then_end, depth + 1);
+                self.line(depth, "}".to_string());
+                Some(then_end)
+            }
+        }
+    }
+
+    /// Find the latest jump back to an instruction, within `index..end`
+    fn latch(&self, index: usize, end: usize) -> Option<usize> {
+        let header = Exit::Jump(self.instructions[index].address);
+        (index..end)
+            .rev()
+            .find(|&i| self.exits(i).contains(&header))
+    }
+
+    /// Check if an instruction is the return address store just before a call
+    fn is_call_setup(&self, index: usize, end: usize) -> bool {
+        index + 1 < end
+            && self.instructions[index + 1].address == self.instructions[index].next()
+            && matches!(self.exits(index + 1).first(), Some(Exit::Call(_)))
+    }
+
+    /// Find the index of the instruction at an address, if it's within `..=end`
+    fn find(&self, address: usize, end: usize) -> Option<usize> {
+        let index = self
+            .instructions
+            .iter()
+            .position(|i| i.address >= address)
+            .unwrap_or(self.instructions.len());
+
+        match self.instructions.get(index) {
+            _ if index > end => None,
+            Some(instruction) if instruction.address != address => None,
+            _ => Some(index),
+        }
+    }
+
+    fn goto(&mut self, target: usize) -> String {
+        match self.loops.last() {
+            Some((_, exit)) if *exit == target => "break;".to_string(),
+            Some((header, _)) if *header == target => "continue;".to_string(),
+            _ => {
+                self.gotos.insert(target);
+                format!("goto label_{};", target)
+            }
+        }
+    }
+
+    fn exits(&self, index: usize) -> &'a [Exit] {
+        self.exits
+            .get(&self.instructions[index].address)
+            .copied()
+            .unwrap_or(&[])
+    }
+
+    /// Check if a jump might or might not be taken
+    fn conditional(&self, index: usize) -> bool {
+        let exits = self.exits(index);
+        exits.len() > 1 && exits.iter().any(|exit| matches!(exit, Exit::Next(_)))
+    }
+
+    /// Get the condition under which a jump is taken, or not taken if `negate`
+    fn condition(&self, index: usize, negate: bool) -> String {
+        let instruction = self.instructions[index];
+        let jump_if = matches!(instruction.opcode, Opcode::JumpIfTrue(..));
+        let comparison = if jump_if != negate { "!=" } else { "==" };
+
+        format!("{} {} 0", operand(instruction, 0), comparison)
+    }
+
+    fn line(&mut self, depth: usize, text: String) {
+        self.lines.push(Line::Statement(depth, text));
+    }
+
+    fn finish(self) -> String {
+        let mut out = String::new();
+        let mut labelled = BTreeSet::new();
+
+        for line in self.lines {
+            match line {
+                Line::Label(depth, address)
+                    if self.gotos.contains(&address) && labelled.insert(address) =>
+                {
+                    writeln!(out, "{:1$}label_{2}:", "", (depth - 1) * 4, address).unwrap()
+                }
+                Line::Label(..) => {}
+                Line::Statement(depth, text) => {
+                    writeln!(out, "{:1$}{2}", "", depth * 4, text).unwrap()
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn is_immediate(instruction: &Instruction, index: usize) -> bool {
+    instruction.opcode.modes()[index] == ParameterMode::Immediate
+}
+
+/// Get the pseudocode for a parameter
+fn operand(instruction: &Instruction, index: usize) -> String {
+    let value = instruction.parameters[index];
+
+    match instruction.opcode.modes()[index] {
+        ParameterMode::Position if value >= 0 => format!("v{}", value),
+        ParameterMode::Position => format!("mem[{}]", value),
+        ParameterMode::Immediate => value.to_string(),
+        ParameterMode::Relative => format!("rb[{}]", value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn calls() {
+        let mut memory = vec![
+            109, 100, // Set up the stack
+            21101, 7, 0, 1, // Argument
+            21101, 13, 0, 0, // Return address
+            1105, 1, 16, // Call
+            4, 50, 99, // Output the result
+            1202, 1, 2, 50, // Double the argument
+            2105, 1, 0, // Return
+        ];
+        memory.resize(102, 0);
+
+        assert_eq!(
+            decompile(&memory),
+            "var v50 = 0;\n\
+             \n\
+             fn main() {\n    \
+                 rb += 100;\n    \
+                 rb[1] = 7;\n    \
+                 call func_16();\n    \
+                 output(v50);\n    \
+                 halt();\n\
+             }\n\
+             \n\
+             fn func_16() {\n    \
+                 v50 = rb[1] * 2;\n    \
+                 return;\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn while_loop() {
+        // Count down from the input to 1
+        let mut memory = vec![3, 20, 1006, 20, 14, 4, 20, 1001, 20, -1, 20, 1105, 1, 2, 99];
+        memory.resize(21, 0);
+
+        assert_eq!(
+            decompile(&memory),
+            "var v20 = 0;\n\
+             \n\
+             fn main() {\n    \
+                 v20 = input();\n    \
+                 while (v20 != 0) {\n        \
+                     output(v20);\n        \
+                     v20 = v20 + -1;\n    \
+                 }\n    \
+                 halt();\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn if_else_and_goto() {
+        // Output 1 if the input is 8, otherwise 0
+        let mut memory = vec![
+            3, 30, 1008, 30, 8, 31, 1005, 31, 14, 104, 0, 1105, 1, 16, 104, 1, 99,
+        ];
+        memory.resize(32, 0);
+
+        assert_eq!(
+            decompile(&memory),
+            "var v30 = 0;\n\
+             var v31 = 0;\n\
+             \n\
+             fn main() {\n    \
+                 v30 = input();\n    \
+                 v31 = v30 == 8;\n    \
+                 if (v31 == 0) {\n        \
+                     output(0);\n    \
+                 } else {\n        \
+                     output(1);\n    \
+                 }\n    \
+                 halt();\n\
+             }\n"
+        );
+
+        // A jump back into the middle of an if can't be structured
+        let mut memory = vec![
+            3, 20, 1005, 20, 9, 104, 1, 104, 2, 1001, 20, -1, 20, 1005, 20, 7, 99,
+        ];
+        memory.resize(21, 0);
+        let code = decompile(&memory);
+        assert!(
+            code.contains("\n    label_7:\n        output(2);\n"),
+            "{}",
+            code
+        );
+        assert!(
+            code.contains("    if (v20 != 0) goto label_7;\n"),
+            "{}",
+            code
+        );
+    }
+}
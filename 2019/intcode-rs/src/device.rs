@@ -0,0 +1,204 @@
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// Hardware emulated behind a range of memory addresses
+///
+/// While a device is mapped into a VM with `IntcodeVM::map_device`, every
+/// read and write of an address in its range goes to the device instead of
+/// memory. That includes cells used through instruction parameters as well as
+/// `get_memory` and `set_memory`. Instructions themselves are always fetched
+/// from plain memory, and `IntcodeVM::peek` and `IntcodeVM::memory` always
+/// show plain memory, so looking at a program never disturbs its devices.
+///
+/// Clones of a VM share its devices, like two views of the same hardware.
+///
+/// Offsets are counted from the start of the device's range.
+pub trait Device<W = i64> {
+    /// Get the value of a cell
+    fn read(&mut self, offset: usize) -> W;
+
+    /// Set the value of a cell
+    fn write(&mut self, offset: usize, value: W);
+}
+
+/// A device mapped into a VM's memory
+#[derive(Clone)]
+pub(crate) struct Mapping<W> {
+    pub range: Range<usize>,
+    pub device: Arc<Mutex<dyn Device<W> + Send>>,
+}
+
+impl<W> fmt::Debug for Mapping<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mapping")
+            .field("range", &self.range)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A grid of pixels, stored row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Framebuffer {
+    width: usize,
+    pixels: Vec<i64>,
+}
+
+impl Framebuffer {
+    /// Create a framebuffer with every pixel set to 0
+    ///
+    /// Map it to a range of `width * height` addresses.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    /// Get the value of a pixel
+    pub fn pixel(&self, x: usize, y: usize) -> i64 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Draw the framebuffer as text, with `#` for non-zero pixels and `.` for zero
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        for row in self.pixels.chunks(self.width) {
+            out.extend(row.iter().map(|p| if *p != 0 { '#' } else { '.' }));
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: usize) -> i64 {
+        self.pixels.get(offset).copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, offset: usize, value: i64) {
+        if let Some(pixel) = self.pixels.get_mut(offset) {
+            *pixel = value;
+        }
+    }
+}
+
+/// A counter that ticks every time it's read
+///
+/// Reading gives the number of earlier reads; writing sets the count.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Clock {
+    pub ticks: i64,
+}
+
+impl Device for Clock {
+    fn read(&mut self, _offset: usize) -> i64 {
+        self.ticks += 1;
+        self.ticks - 1
+    }
+
+    fn write(&mut self, _offset: usize, value: i64) {
+        self.ticks = value;
+    }
+}
+
+/// A source of non-negative pseudo-random numbers
+///
+/// Every read gives a new number. Writing a value reseeds it, so programs can
+/// be made to repeat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    /// Create a random number source from a seed
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero
+        Self { state: seed.max(1) }
+    }
+}
+
+impl Device for Random {
+    fn read(&mut self, _offset: usize) -> i64 {
+        self.state = xorshift(self.state);
+        (self.state >> 1) as i64
+    }
+
+    fn write(&mut self, _offset: usize, value: i64) {
+        *self = Self::new(value as u64);
+    }
+}
+
+/// Advance a xorshift random number generator
+pub(crate) fn xorshift(mut x: u64) -> u64 {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::IntcodeVM;
+
+    #[test]
+    fn framebuffer() {
+        // Draw a diagonal line, with the framebuffer at 100
+        let mut vm = IntcodeVM::new(vec![
+            1101, 1, 0, 100, // (0, 0)
+            1101, 1, 0, 104, // (1, 1)
+            1101, 1, 0, 108, // (2, 2)
+            4, 104, // Output the middle pixel back
+            99,
+        ]);
+        let screen = vm.map_device(100..109, Framebuffer::new(3, 3));
+        vm.run_to_end().unwrap();
+
+        assert_eq!(screen.lock().unwrap().render(), "#..\n.#.\n..#\n");
+        assert_eq!(screen.lock().unwrap().pixel(1, 1), 1);
+        assert_eq!(vm.pop_output(), Some(1));
+        assert_eq!(vm.get_memory(109), Err(crate::ExecutionError::InvalidPC));
+    }
+
+    #[test]
+    fn clock_and_random() {
+        // Output the clock twice and two constants, then two random numbers after seeding
+        let mut vm = IntcodeVM::new(vec![
+            4, 50, 4, 50, 104, 1, 104, 2, 1101, 0, 7, 51, 4, 51, 4, 51, 99,
+        ]);
+        vm.map_device(50..51, Clock::default());
+        vm.map_device(51..52, Random::new(1));
+        vm.run_to_end().unwrap();
+
+        let outputs: Vec<_> = vm.iter_output().copied().collect();
+        assert_eq!(&outputs[..4], &[0, 1, 1, 2]);
+        assert!(outputs[4] >= 0 && outputs[5] >= 0);
+        assert_ne!(outputs[4], outputs[5]);
+
+        let mut random = Random::new(7);
+        assert_eq!(&outputs[4..], &[random.read(0), random.read(0)]);
+    }
+
+    #[test]
+    fn peek_and_clones() {
+        let mut vm = IntcodeVM::new(vec![4, 3, 99, 0]);
+        let clock = vm.map_device(3..4, Clock::default());
+
+        // Peeking sees plain memory and leaves the clock alone
+        assert_eq!(vm.peek(3), Ok(0));
+        assert_eq!(vm.peek(4), Err(crate::ExecutionError::InvalidPC));
+        assert_eq!(clock.lock().unwrap().ticks, 0);
+
+        // Clones drive the same clock
+        let mut clone = vm.clone();
+        clone.run_to_end().unwrap();
+        vm.run_to_end().unwrap();
+        assert_eq!((clone.pop_output(), vm.pop_output()), (Some(0), Some(1)));
+        assert_eq!(vm.get_memory(3), Ok(2));
+        assert_eq!(clock.lock().unwrap().ticks, 3);
+    }
+}
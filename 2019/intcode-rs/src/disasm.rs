@@ -0,0 +1,190 @@
+use crate::isa::{InstructionSet, Intcode, Role, Signature};
+use crate::{ExecutionError, Opcode, ParameterMode, Result};
+use std::fmt;
+
+/// A single decoded instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    /// The address of the opcode
+    pub address: usize,
+    pub opcode: Opcode,
+    /// The raw parameter values, in order
+    pub parameters: Vec<i64>,
+}
+
+impl Instruction {
+    /// Decode the instruction at an address
+    ///
+    /// Returns `Err(InvalidPC)` if the address or any of the instruction's
+    /// parameters are past the end of memory.
+    pub fn decode(memory: &[i64], address: usize) -> Result<Self> {
+        let opcode = Opcode::from_raw(*memory.get(address).ok_or(ExecutionError::InvalidPC)?)?;
+        let parameters = memory
+            .get(address + 1..address + opcode.size())
+            .ok_or(ExecutionError::InvalidPC)?
+            .to_vec();
+
+        Ok(Self {
+            address,
+            opcode,
+            parameters,
+        })
+    }
+
+    /// Get the address just past the end of the instruction
+    pub fn next(&self) -> usize {
+        self.address + self.opcode.size()
+    }
+
+    /// Get the index of the parameter the instruction writes to, if any
+    pub fn write_parameter(&self) -> Option<usize> {
+        signature(&self.opcode)
+            .roles
+            .iter()
+            .position(|role| *role == Role::Write)
+    }
+
+    /// Get the address of the memory cell a parameter refers to
+    ///
+    /// Immediate parameters refer to the cell holding the parameter itself.
+    /// Returns `None` for position parameters holding negative addresses, and
+    /// for relative parameters, which depend on the relative base at runtime.
+    pub fn parameter_address(&self, index: usize) -> Option<usize> {
+        match self.opcode.modes()[index] {
+            ParameterMode::Immediate => Some(self.address + 1 + index),
+            ParameterMode::Position if self.parameters[index] >= 0 => {
+                Some(self.parameters[index] as usize)
+            }
+            ParameterMode::Position | ParameterMode::Relative => None,
+        }
+    }
+}
+
+/// Get the short name of an opcode, as used in disassembly
+pub fn mnemonic(opcode: &Opcode) -> &'static str {
+    signature(opcode).mnemonic
+}
+
+/// Look up the signature of an opcode in the built-in instruction set
+fn signature(opcode: &Opcode) -> Signature {
+    let code = match opcode {
+        Opcode::Add(..) => 1,
+        Opcode::Multiply(..) => 2,
+        Opcode::Input(..) => 3,
+        Opcode::Output(..) => 4,
+        Opcode::JumpIfTrue(..) => 5,
+        Opcode::JumpIfFalse(..) => 6,
+        Opcode::LessThan(..) => 7,
+        Opcode::Equals(..) => 8,
+        Opcode::AdjustRelativeBase(..) => 9,
+        Opcode::Halt => 99,
+    };
+
+    InstructionSet::<i64>::signature(&Intcode, code).unwrap()
+}
+
+/// Formats as the mnemonic followed by the parameters, with position
+/// parameters in square brackets and relative parameters offset from `rb`:
+/// `add [9], 3, [rb-2]`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", mnemonic(&self.opcode))?;
+
+        for (i, (mode, value)) in self.opcode.modes().iter().zip(&self.parameters).enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+
+            match mode {
+                ParameterMode::Position => write!(f, "{}[{}]", separator, value)?,
+                ParameterMode::Immediate => write!(f, "{}{}", separator, value)?,
+                ParameterMode::Relative => write!(f, "{}[rb{:+}]", separator, value)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Disassemble memory one instruction after another, starting from address 0
+///
+/// Cells that don't decode as an instruction are shown as `data`. This has no
+/// way of telling code from data, so use `cfg::Cfg::listing` to only decode
+/// reachable code.
+pub fn disassemble(memory: &[i64]) -> String {
+    listing(memory, |_| true)
+}
+
+/// Disassemble memory, decoding instructions only at addresses where `is_code` holds
+pub(crate) fn listing<F: Fn(usize) -> bool>(memory: &[i64], is_code: F) -> String {
+    let mut out = String::new();
+
+    for (address, instruction) in lines(memory, is_code) {
+        match instruction {
+            Some(instruction) => out += &format!("{:>6}: {}\n", address, instruction),
+            None => out += &format!("{:>6}: data {}\n", address, memory[address]),
+        }
+    }
+
+    out
+}
+
+/// Get each line of a listing, with the instruction if it's code
+pub(crate) fn lines<F: Fn(usize) -> bool>(
+    memory: &[i64],
+    is_code: F,
+) -> Vec<(usize, Option<Instruction>)> {
+    let mut lines = Vec::new();
+    let mut address = 0;
+
+    while address < memory.len() {
+        match Instruction::decode(memory, address) {
+            Ok(instruction) if is_code(address) => {
+                address = instruction.next();
+                lines.push((instruction.address, Some(instruction)));
+            }
+            _ => {
+                lines.push((address, None));
+                address += 1;
+            }
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let memory = [1002, 4, 3, 4, 33];
+
+        let instruction = Instruction::decode(&memory, 0).unwrap();
+        assert_eq!(instruction.parameters, vec![4, 3, 4]);
+        assert_eq!(instruction.next(), 4);
+        assert_eq!(instruction.parameter_address(0), Some(4));
+        assert_eq!(instruction.parameter_address(1), Some(2));
+        assert_eq!(instruction.to_string(), "mul [4], 3, [4]");
+
+        let relative = Instruction::decode(&[21201, -2, 1, 0], 0).unwrap();
+        assert_eq!(relative.to_string(), "add [rb-2], 1, [rb+0]");
+        assert_eq!(relative.parameter_address(0), None);
+
+        assert_eq!(
+            Instruction::decode(&memory, 4),
+            Err(ExecutionError::UnknownOpcode(33))
+        );
+        assert_eq!(
+            Instruction::decode(&memory[..3], 0),
+            Err(ExecutionError::InvalidPC)
+        );
+    }
+
+    #[test]
+    fn linear_sweep() {
+        assert_eq!(
+            disassemble(&[3, 0, 4, 0, 99, 7]),
+            "     0: in [0]\n     2: out [0]\n     4: halt\n     5: data 7\n"
+        );
+    }
+}
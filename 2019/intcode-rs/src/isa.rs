@@ -0,0 +1,295 @@
+use crate::{ExecutionError, IntcodeVM, Machine, Opcode, Result, Word};
+use std::collections::BTreeMap;
+
+/// How an instruction uses one of its parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The parameter is a value to read
+    Read,
+    /// The parameter says where to write a result, so it can't be immediate
+    Write,
+}
+
+/// The shape of an opcode: its name and what each of its parameters is for
+///
+/// The arity of the opcode is the number of roles, and the VM moves past
+/// that many parameters after running it. Before running it, the VM fails
+/// with `ImmediateModeWrite` if a `Write` parameter is in immediate mode. The
+/// disassembler takes the names and roles of built-in opcodes from `Intcode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub mnemonic: &'static str,
+    pub roles: &'static [Role],
+}
+
+impl Signature {
+    /// Get the number of parameters
+    pub fn arity(&self) -> usize {
+        self.roles.len()
+    }
+}
+
+/// Where execution goes after an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// Continue with the instruction after this one
+    Next,
+    /// Continue at an address
+    Jump(usize),
+    /// Stop the program
+    Halt,
+}
+
+/// A set of opcodes a VM with cells of type `W` can run
+///
+/// Opcodes are the last two digits of a raw instruction value, with parameter
+/// modes in the digits above them, as usual. The VM looks up each opcode's
+/// signature, checks its parameter modes, and then calls `execute` with the
+/// PC still pointing at the instruction.
+pub trait InstructionSet<W: Word = i64> {
+    /// Get the signature of an opcode, or `None` if it isn't in the set
+    fn signature(&self, opcode: i64) -> Option<Signature>;
+
+    /// Run an instruction
+    ///
+    /// `raw` is the instruction value, including parameter modes, as given
+    /// by `Word::instruction`. Parameters can be read and written with
+    /// `Machine::get_parameter` and `Machine::set_parameter`, with offsets
+    /// starting from 1.
+    fn execute(&self, vm: &mut Machine<W>, raw: i64) -> Result<Control>;
+}
+
+const READ_READ_WRITE: &[Role] = &[Role::Read, Role::Read, Role::Write];
+
+/// The instruction set from the puzzles, as run by `IntcodeVM::step`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Intcode;
+
+impl<W: Word> InstructionSet<W> for Intcode {
+    fn signature(&self, opcode: i64) -> Option<Signature> {
+        let (mnemonic, roles) = match opcode {
+            1 => ("add", READ_READ_WRITE),
+            2 => ("mul", READ_READ_WRITE),
+            3 => ("in", &[Role::Write][..]),
+            4 => ("out", &[Role::Read][..]),
+            5 => ("jt", &[Role::Read, Role::Read][..]),
+            6 => ("jf", &[Role::Read, Role::Read][..]),
+            7 => ("lt", READ_READ_WRITE),
+            8 => ("eq", READ_READ_WRITE),
+            9 => ("arb", &[Role::Read][..]),
+            99 => ("halt", &[][..]),
+            _ => return None,
+        };
+
+        Some(Signature { mnemonic, roles })
+    }
+
+    fn execute(&self, vm: &mut Machine<W>, raw: i64) -> Result<Control> {
+        match Opcode::from_raw(raw)? {
+            Opcode::Add(in1, in2, out) => {
+                let val1 = vm.get_parameter(in1, 1)?;
+                let val2 = vm.get_parameter(in2, 2)?;
+                let result = vm
+                    .config
+                    .arithmetic
+                    .add(val1, val2)
+                    .ok_or_else(|| overflow(vm))?;
+                vm.set_parameter(out, 3, result)?;
+            }
+            Opcode::Multiply(in1, in2, out) => {
+                let val1 = vm.get_parameter(in1, 1)?;
+                let val2 = vm.get_parameter(in2, 2)?;
+                let result = vm
+                    .config
+                    .arithmetic
+                    .multiply(val1, val2)
+                    .ok_or_else(|| overflow(vm))?;
+                vm.set_parameter(out, 3, result)?;
+            }
+            Opcode::Input(out) => {
+                let val = vm.input.pop_front().ok_or(ExecutionError::NeedsInput)?;
+                vm.notify(|o| o.on_input(&val));
+                vm.set_parameter(out, 1, val)?;
+            }
+            Opcode::Output(in1) => {
+                let val = vm.get_parameter(in1, 1)?;
+
+                if vm
+                    .config
+                    .limits
+                    .outputs
+                    .is_some_and(|n| vm.output.len() >= n)
+                {
+                    return Err(ExecutionError::OutputLimit);
+                }
+
+                vm.notify(|o| o.on_output(&val));
+                vm.output.push_back(val);
+            }
+            Opcode::JumpIfTrue(in1, in2) | Opcode::JumpIfFalse(in1, in2) => {
+                let val = vm.get_parameter(in1, 1)?;
+                let new_loc = vm.get_parameter(in2, 2)?;
+
+                if (val != W::from(0)) == (raw % 100 == 5) {
+                    return Ok(Control::Jump(Machine::value_to_index(new_loc)?));
+                }
+            }
+            Opcode::LessThan(in1, in2, out) => {
+                let val1 = vm.get_parameter(in1, 1)?;
+                let val2 = vm.get_parameter(in2, 2)?;
+                let result = W::from(if val1 < val2 { 1 } else { 0 });
+                vm.set_parameter(out, 3, result)?;
+            }
+            Opcode::Equals(in1, in2, out) => {
+                let val1 = vm.get_parameter(in1, 1)?;
+                let val2 = vm.get_parameter(in2, 2)?;
+                let result = W::from(if val1 == val2 { 1 } else { 0 });
+                vm.set_parameter(out, 3, result)?;
+            }
+            Opcode::AdjustRelativeBase(in1) => {
+                let val = vm.get_parameter(in1, 1)?;
+                vm.relative_base = vm
+                    .config
+                    .arithmetic
+                    .add(vm.relative_base.clone(), val)
+                    .ok_or_else(|| overflow(vm))?;
+            }
+            Opcode::Halt => return Ok(Control::Halt),
+        }
+
+        Ok(Control::Next)
+    }
+}
+
+fn overflow<W: Word>(vm: &Machine<W>) -> ExecutionError {
+    ExecutionError::Overflow { pc: vm.pc }
+}
+
+type Handler = Box<dyn Fn(&mut IntcodeVM, i64) -> Result<Control> + Send + Sync>;
+
+/// An instruction set with extra opcodes added on top of another
+///
+/// Registered opcodes take priority over the base set's, so they can also
+/// replace built-in instructions.
+pub struct Extended<I = Intcode> {
+    base: I,
+    extra: BTreeMap<i64, (Signature, Handler)>,
+}
+
+impl<I: InstructionSet> Extended<I> {
+    /// Start extending an instruction set
+    pub fn new(base: I) -> Self {
+        Self {
+            base,
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// Add an opcode, which runs `execute` just like `InstructionSet::execute`
+    pub fn register<F>(&mut self, opcode: i64, signature: Signature, execute: F)
+    where
+        F: Fn(&mut IntcodeVM, i64) -> Result<Control> + Send + Sync + 'static,
+    {
+        self.extra.insert(opcode, (signature, Box::new(execute)));
+    }
+}
+
+impl<I: InstructionSet> InstructionSet for Extended<I> {
+    fn signature(&self, opcode: i64) -> Option<Signature> {
+        match self.extra.get(&opcode) {
+            Some((signature, _)) => Some(*signature),
+            None => self.base.signature(opcode),
+        }
+    }
+
+    fn execute(&self, vm: &mut IntcodeVM, raw: i64) -> Result<Control> {
+        match self.extra.get(&(raw % 100)) {
+            Some((_, execute)) => execute(vm, raw),
+            None => self.base.execute(vm, raw),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ParameterMode;
+
+    fn with_max() -> Extended {
+        let mut isa = Extended::new(Intcode);
+        isa.register(
+            10,
+            Signature {
+                mnemonic: "max",
+                roles: &[Role::Read, Role::Read, Role::Write],
+            },
+            |vm, raw| {
+                let a = vm.get_parameter(ParameterMode::from_opcode(raw, 0)?, 1)?;
+                let b = vm.get_parameter(ParameterMode::from_opcode(raw, 1)?, 2)?;
+                vm.set_parameter(ParameterMode::from_opcode(raw, 2)?, 3, a.max(b))?;
+                Ok(Control::Next)
+            },
+        );
+        isa
+    }
+
+    #[test]
+    fn extra_opcode() {
+        let isa = with_max();
+        assert_eq!(isa.signature(10).map(|s| s.arity()), Some(3));
+        assert_eq!(isa.signature(1).map(|s| s.mnemonic), Some("add"));
+
+        let mut vm = IntcodeVM::new(vec![3, 11, 1010, 11, 5, 11, 4, 11, 99, 0, 0, 0]);
+        vm.push_input(2);
+        vm.run_to_end_with(&isa).unwrap();
+        assert_eq!(vm.pop_output(), Some(5));
+
+        let mut vm = IntcodeVM::new(vec![1010, 11, 5, 11, 99]);
+        assert_eq!(vm.step(), Err(ExecutionError::UnknownOpcode(10)));
+        assert_eq!(
+            IntcodeVM::new(vec![31010, 0, 0, 0]).step_with(&isa),
+            Err(ExecutionError::UnknownMode(3))
+        );
+
+        // A write parameter can't be immediate, even if the handler ignores it
+        let mut isa = Extended::new(Intcode);
+        isa.register(
+            11,
+            Signature {
+                mnemonic: "stop",
+                roles: &[Role::Write],
+            },
+            |_, _| Ok(Control::Halt),
+        );
+        assert_eq!(IntcodeVM::new(vec![11, 0]).step_with(&isa), Ok(false));
+        assert_eq!(
+            IntcodeVM::new(vec![111, 0]).step_with(&isa),
+            Err(ExecutionError::ImmediateModeWrite)
+        );
+    }
+
+    #[test]
+    fn replace_opcode() {
+        // Make halt jump back to the start instead, three times
+        let mut isa = Extended::new(Intcode);
+        isa.register(
+            99,
+            Signature {
+                mnemonic: "halt",
+                roles: &[],
+            },
+            |vm, _| {
+                if vm.iter_output().count() < 3 {
+                    Ok(Control::Jump(0))
+                } else {
+                    Ok(Control::Halt)
+                }
+            },
+        );
+
+        let mut vm = IntcodeVM::new(vec![104, 1, 99]);
+        vm.run_to_end_with(&isa).unwrap();
+        assert_eq!(vm.iter_output().count(), 3);
+        assert_eq!(vm.pc(), 2);
+    }
+}
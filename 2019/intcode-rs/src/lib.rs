@@ -1,14 +1,55 @@
+pub mod cfg;
+pub mod config;
+pub mod coverage;
+pub mod cycle;
+pub mod decompile;
+pub mod device;
+pub mod disasm;
+pub mod isa;
+pub mod network;
+pub mod observer;
+pub mod profile;
+pub mod protect;
+pub mod record;
+pub mod scheduler;
+pub mod symbolic;
+pub mod syscall;
+pub mod taint;
+pub mod threaded;
+pub mod validate;
+pub mod word;
+
+#[cfg(feature = "async")]
+pub mod async_vm;
+
+#[cfg(feature = "rayon")]
+pub mod search;
+
+use isa::InstructionSet;
 use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+pub use word::Word;
 
+/// An Intcode machine, with memory cells of type `W`
 #[derive(Debug, Clone)]
-pub struct IntcodeVM {
-    memory: Vec<i64>,
+pub struct Machine<W: Word = i64> {
+    memory: Vec<W>,
     pc: usize,
+    relative_base: W,
     halted: bool,
-    input: VecDeque<i64>,
-    output: VecDeque<i64>,
+    input: VecDeque<W>,
+    output: VecDeque<W>,
+    devices: Vec<device::Mapping<W>>,
+    config: config::VmConfig,
+    steps: u64,
+    cancel: config::CancelToken,
+    observers: Vec<observer::Attached<W>>,
 }
 
+/// The usual Intcode machine, with 64-bit cells
+pub type IntcodeVM = Machine<i64>;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ExecutionError {
     InvalidPC,
@@ -18,6 +59,16 @@ pub enum ExecutionError {
     ImmediateModeWrite,
     UnknownOpcode(i64),
     UnknownMode(u8),
+    UnknownSyscall(i64),
+    InvalidSyscallArgument(i64),
+    UnsupportedOpcode(i64),
+    UnsupportedMode(u8),
+    Overflow { pc: usize },
+    MemoryLimit,
+    OutputLimit,
+    StepLimit,
+    DeadlineExceeded,
+    Cancelled,
 }
 
 type Result<T> = std::result::Result<T, ExecutionError>;
@@ -26,17 +77,19 @@ type Result<T> = std::result::Result<T, ExecutionError>;
 pub enum ParameterMode {
     Position,
     Immediate,
+    Relative,
 }
 
 impl ParameterMode {
-    pub fn from_opcode(opcode: i64, parameter_index: u32) -> Result<Self> {
+    pub fn from_opcode<W: Word>(opcode: W, parameter_index: u32) -> Result<Self> {
         let place_value = 10i64.pow(parameter_index + 2);
 
-        let mode_value = ((opcode / place_value) % 10) as u8;
+        let mode_value = ((opcode.instruction() / place_value) % 10) as u8;
 
         match mode_value {
             0 => Ok(ParameterMode::Position),
             1 => Ok(ParameterMode::Immediate),
+            2 => Ok(ParameterMode::Relative),
             unknown => Err(ExecutionError::UnknownMode(unknown)),
         }
     }
@@ -52,6 +105,7 @@ pub enum Opcode {
     JumpIfFalse(ParameterMode, ParameterMode),
     LessThan(ParameterMode, ParameterMode, ParameterMode),
     Equals(ParameterMode, ParameterMode, ParameterMode),
+    AdjustRelativeBase(ParameterMode),
     Halt,
 }
 
@@ -60,7 +114,9 @@ impl Opcode {
     ///
     /// Invalid parameter modes (for example, immediate mode for an output) will
     /// *not* return `Err`, but may cause an error when run.
-    pub fn from_raw(raw: i64) -> Result<Self> {
+    pub fn from_raw<W: Word>(raw: W) -> Result<Self> {
+        let raw = raw.instruction();
+
         match raw % 100 {
             1 => Ok(Self::Add(
                 ParameterMode::from_opcode(raw, 0)?,
@@ -92,79 +148,244 @@ impl Opcode {
                 ParameterMode::from_opcode(raw, 1)?,
                 ParameterMode::from_opcode(raw, 2)?,
             )),
+            9 => Ok(Self::AdjustRelativeBase(ParameterMode::from_opcode(
+                raw, 0,
+            )?)),
             99 => Ok(Self::Halt),
             unknown => Err(ExecutionError::UnknownOpcode(unknown)),
         }
     }
+
+    /// Get the number of memory cells the instruction takes up, including the opcode
+    pub fn size(&self) -> usize {
+        1 + self.modes().len()
+    }
+
+    /// Get the modes of each parameter, in order
+    pub fn modes(&self) -> Vec<ParameterMode> {
+        use Opcode::*;
+
+        match *self {
+            Add(a, b, c) | Multiply(a, b, c) | LessThan(a, b, c) | Equals(a, b, c) => vec![a, b, c],
+            Input(a) | Output(a) | AdjustRelativeBase(a) => vec![a],
+            JumpIfTrue(a, b) | JumpIfFalse(a, b) => vec![a, b],
+            Halt => vec![],
+        }
+    }
 }
 
-impl IntcodeVM {
+impl<W: Word> Machine<W> {
     /// Create a new VM from some existing memory
-    pub fn new<D: Into<Vec<i64>>>(data: D) -> Self {
+    pub fn new<D: Into<Vec<W>>>(data: D) -> Self {
+        Self::build(data.into(), config::VmConfig::default())
+    }
+
+    /// Create a new VM from some existing memory, with non-default settings
+    ///
+    /// Fails with `MemoryLimit` if there's more memory than the limits allow.
+    /// Memory never grows while running, so this is the only check on it.
+    pub fn with_config<D: Into<Vec<W>>>(data: D, config: config::VmConfig) -> Result<Self> {
+        let memory = data.into();
+
+        if config
+            .limits
+            .memory
+            .is_some_and(|cells| memory.len() > cells)
+        {
+            return Err(ExecutionError::MemoryLimit);
+        }
+
+        Ok(Self::build(memory, config))
+    }
+
+    fn build(memory: Vec<W>, config: config::VmConfig) -> Self {
         Self {
-            memory: data.into(),
+            memory,
             pc: 0,
+            relative_base: W::from(0),
             halted: false,
             input: VecDeque::new(),
             output: VecDeque::new(),
+            devices: Vec::new(),
+            config,
+            steps: 0,
+            cancel: config::CancelToken::new(),
+            observers: Vec::new(),
         }
     }
 
+    /// Make a VM from a comma-separated list of integers
+    pub fn parse(text: &str) -> std::result::Result<Self, W::Err> {
+        let mut data = Vec::new();
+
+        for item in text.trim().split(',') {
+            data.push(item.trim().parse()?)
+        }
+
+        Ok(Self::new(data))
+    }
+
     /// Read a comma-separated list of integers from `stdin` and make it into a VM
-    pub fn from_stdin() -> std::io::Result<Self> {
+    pub fn from_stdin() -> std::io::Result<Self>
+    where
+        W::Err: std::error::Error + Send + Sync + 'static,
+    {
         use std::io::prelude::*;
 
         let mut buffer = String::new();
         std::io::stdin().read_to_string(&mut buffer)?;
 
-        let mut data = Vec::new();
-
-        for item in buffer.trim().split(',') {
-            let num: i64 = item
-                .parse()
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, Box::new(e)))?;
-            data.push(num)
-        }
-
-        Ok(Self::new(data))
+        Self::parse(&buffer).map_err(std::io::Error::other)
     }
 
     /// Add a single input value to the end of the input queue
-    pub fn push_input(&mut self, input: i64) {
+    pub fn push_input(&mut self, input: W) {
         self.input.push_back(input)
     }
 
     /// Add input values from an interator to the end of the input queue
-    pub fn push_inputs<I: IntoIterator<Item = i64>>(&mut self, input: I) {
+    pub fn push_inputs<I: IntoIterator<Item = W>>(&mut self, input: I) {
         self.input.extend(input)
     }
 
     /// Pop values off the front of the output queue
-    pub fn pop_output(&mut self) -> Option<i64> {
+    pub fn pop_output(&mut self) -> Option<W> {
         self.output.pop_front()
     }
 
     /// Get an interator over the output queue
-    pub fn iter_output<'a>(&'a self) -> impl Iterator<Item = &i64> {
+    pub fn iter_output(&self) -> impl Iterator<Item = &W> {
         self.output.iter()
     }
 
+    /// Get the settings the VM runs with
+    pub fn config(&self) -> &config::VmConfig {
+        &self.config
+    }
+
+    /// Get a token that stops the VM when cancelled, even from another thread
+    ///
+    /// Clones of the VM share the same token.
+    pub fn cancel_token(&self) -> config::CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Replace the VM's cancellation token, for example to share one between VMs
+    pub fn set_cancel_token(&mut self, token: config::CancelToken) {
+        self.cancel = token;
+    }
+
+    /// Get the number of instructions run so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Get the current PC
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Get the current relative base
+    pub fn relative_base(&self) -> W {
+        self.relative_base.clone()
+    }
+
     /// Get the raw opcode value pointed to by the current PC
-    pub fn current_raw_opcode(&self) -> Result<i64> {
-        self.get_memory(self.pc)
-            .map_err(|_| ExecutionError::InvalidPC)
+    pub fn current_raw_opcode(&self) -> Result<W> {
+        self.peek(self.pc)
+    }
+
+    /// Map a device over a range of addresses, so reads and writes there go to it
+    ///
+    /// The range can go past the end of memory. Later mappings take priority
+    /// where they overlap earlier ones. Returns a handle for looking at the
+    /// device from outside the VM; clones of the VM share the same device.
+    pub fn map_device<D: device::Device<W> + Send + 'static>(
+        &mut self,
+        range: Range<usize>,
+        device: D,
+    ) -> Arc<Mutex<D>> {
+        let device = Arc::new(Mutex::new(device));
+
+        self.devices.push(device::Mapping {
+            range,
+            device: device.clone(),
+        });
+
+        device
+    }
+
+    /// Attach an observer, which is told about everything the VM does from now on
+    ///
+    /// Observers are told about events in the order they were attached. Returns
+    /// a handle for looking at the observer from outside the VM; clones of the
+    /// VM share the same observers, but copies made with `detached` don't.
+    pub fn observe<O: observer::Observer<W> + Send + 'static>(
+        &mut self,
+        observer: O,
+    ) -> Arc<Mutex<O>> {
+        let observer = Arc::new(Mutex::new(observer));
+
+        self.observers.push(observer::Attached(observer.clone()));
+
+        observer
+    }
+
+    /// Detach every observer
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    /// Copy the VM without its devices, observers or cancellation token
+    ///
+    /// A plain `clone` shares all three with the original, so running it
+    /// drives the same devices, reports to the same observers and stops when
+    /// the original is cancelled. A detached copy runs on its own, with plain
+    /// memory where devices were mapped and a fresh token, which suits running
+    /// ahead without anyone noticing.
+    pub fn detached(&self) -> Self {
+        Self {
+            devices: Vec::new(),
+            observers: Vec::new(),
+            cancel: config::CancelToken::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Get the value of plain memory at a given index, without touching devices
+    ///
+    /// Unlike `get_memory`, this never has side effects, so it's safe to use
+    /// for looking at a program without changing how it runs.
+    pub fn peek(&self, index: usize) -> Result<W> {
+        self.memory
+            .get(index)
+            .cloned()
+            .ok_or(ExecutionError::InvalidPC)
     }
 
     /// Get the value of memory at a given index
-    pub fn get_memory(&self, index: usize) -> Result<i64> {
+    ///
+    /// Reads from a mapped device go to the device, which may change its state.
+    pub fn get_memory(&self, index: usize) -> Result<W> {
+        if let Some(mapping) = self.device_at(index) {
+            let offset = index - mapping.range.start;
+            return Ok(mapping.device.lock().unwrap().read(offset));
+        }
+
         self.memory
             .get(index)
-            .map(|v| *v)
+            .cloned()
             .ok_or(ExecutionError::InvalidPC)
     }
 
     /// Set the value of memory at a given index
-    pub fn set_memory(&mut self, index: usize, value: i64) -> Result<()> {
+    pub fn set_memory(&mut self, index: usize, value: W) -> Result<()> {
+        if let Some(mapping) = self.device_at(index) {
+            let offset = index - mapping.range.start;
+            mapping.device.lock().unwrap().write(offset, value);
+            return Ok(());
+        }
+
         self.memory
             .get_mut(index)
             .map(|v| *v = value)
@@ -172,33 +393,50 @@ impl IntcodeVM {
     }
 
     /// Get the value at the memory location pointed to by the value at the given index
-    pub fn get_memory_by_pointer(&self, index: usize) -> Result<i64> {
+    pub fn get_memory_by_pointer(&self, index: usize) -> Result<W> {
         self.get_memory(Self::value_to_index(self.get_memory(index)?)?)
     }
 
     /// Set the value at the memory location pointed to by the value at the given index
-    pub fn set_memory_by_pointer(&mut self, index: usize, value: i64) -> Result<()> {
+    pub fn set_memory_by_pointer(&mut self, index: usize, value: W) -> Result<()> {
         self.set_memory(Self::value_to_index(self.get_memory(index)?)?, value)
     }
 
     /// Get the parameter based on the given value and the mode
-    pub fn get_parameter(&mut self, mode: ParameterMode, offset: usize) -> Result<i64> {
-        match mode {
-            ParameterMode::Immediate => self.get_memory(self.pc + offset),
-            ParameterMode::Position => self.get_memory_by_pointer(self.pc + offset),
-        }
+    pub fn get_parameter(&mut self, mode: ParameterMode, offset: usize) -> Result<W> {
+        let address = match mode {
+            ParameterMode::Immediate => return self.peek(self.pc + offset),
+            ParameterMode::Position => Self::value_to_index(self.peek(self.pc + offset)?)?,
+            ParameterMode::Relative => self.relative_address(self.peek(self.pc + offset)?)?,
+        };
+
+        let value = self.get_memory(address)?;
+        self.notify(|o| o.on_memory_read(address, &value));
+
+        Ok(value)
     }
 
     /// Set the parameter based on the given value and the mode
-    pub fn set_parameter(&mut self, mode: ParameterMode, offset: usize, value: i64) -> Result<()> {
-        match mode {
-            ParameterMode::Immediate => Err(ExecutionError::ImmediateModeWrite),
-            ParameterMode::Position => self.set_memory_by_pointer(self.pc + offset, value),
+    pub fn set_parameter(&mut self, mode: ParameterMode, offset: usize, value: W) -> Result<()> {
+        let address = match mode {
+            ParameterMode::Immediate => return Err(ExecutionError::ImmediateModeWrite),
+            ParameterMode::Position => Self::value_to_index(self.peek(self.pc + offset)?)?,
+            ParameterMode::Relative => self.relative_address(self.peek(self.pc + offset)?)?,
+        };
+
+        // Only pay for a copy of the value if someone is watching
+        let observed = (!self.observers.is_empty()).then(|| value.clone());
+        self.set_memory(address, value)?;
+
+        if let Some(value) = observed {
+            self.notify(|o| o.on_memory_write(address, &value));
         }
+
+        Ok(())
     }
 
     /// Get the entire memory as a slice
-    pub fn memory(&self) -> &[i64] {
+    pub fn memory(&self) -> &[W] {
         &self.memory
     }
 
@@ -209,73 +447,57 @@ impl IntcodeVM {
     ///
     /// If called again on an already halted program, returns `Err(AlreadyHalted)`.
     pub fn step(&mut self) -> Result<bool> {
+        self.step_with(&isa::Intcode)
+    }
+
+    /// Take a single step through the program, using a different instruction set
+    ///
+    /// Behaves like `step`, but looks up opcodes in `isa`.
+    pub fn step_with<I: isa::InstructionSet<W>>(&mut self, isa: &I) -> Result<bool> {
         if self.halted() {
             return Err(ExecutionError::AlreadyHalted);
         }
 
-        let opcode = Opcode::from_raw(self.current_raw_opcode()?)?;
+        self.check_limits()?;
 
-        match &opcode {
-            &Opcode::Add(in1, in2, out) => {
-                let val1 = self.get_parameter(in1, 1)?;
-                let val2 = self.get_parameter(in2, 2)?;
-                let result = val1 + val2;
-                self.set_parameter(out, 3, result)?;
-                self.pc_advance(&opcode);
-            }
-            &Opcode::Multiply(in1, in2, out) => {
-                let val1 = self.get_parameter(in1, 1)?;
-                let val2 = self.get_parameter(in2, 2)?;
-                let result = val1 * val2;
-                self.set_parameter(out, 3, result)?;
-                self.pc_advance(&opcode);
-            }
-            &Opcode::Input(out) => {
-                let val = self.input.pop_front().ok_or(ExecutionError::NeedsInput)?;
-                self.set_parameter(out, 1, val)?;
-                self.pc_advance(&opcode);
-            }
-            &Opcode::Output(in1) => {
-                let val = self.get_parameter(in1, 1)?;
-                self.output.push_back(val);
-                self.pc_advance(&opcode);
-            }
-            &Opcode::JumpIfTrue(in1, in2) => {
-                let val = self.get_parameter(in1, 1)?;
-                let new_loc = self.get_parameter(in2, 2)?;
-
-                if val != 0 {
-                    self.pc = Self::value_to_index(new_loc)?;
-                } else {
-                    self.pc_advance(&opcode);
-                }
-            }
-            &Opcode::JumpIfFalse(in1, in2) => {
-                let val = self.get_parameter(in1, 1)?;
-                let new_loc = self.get_parameter(in2, 2)?;
-
-                if val == 0 {
-                    self.pc = Self::value_to_index(new_loc)?;
-                } else {
-                    self.pc_advance(&opcode);
-                }
-            }
-            &Opcode::LessThan(in1, in2, out) => {
-                let val1 = self.get_parameter(in1, 1)?;
-                let val2 = self.get_parameter(in2, 2)?;
-                let result = if val1 < val2 { 1 } else { 0 };
-                self.set_parameter(out, 3, result)?;
-                self.pc_advance(&opcode);
+        let raw = self.current_raw_opcode()?.instruction();
+        let level = self.config.level;
+        let builtin = InstructionSet::<W>::signature(&isa::Intcode, raw % 100).is_some();
+
+        if builtin && !level.allows_opcode(raw % 100) {
+            return Err(ExecutionError::UnsupportedOpcode(raw % 100));
+        }
+
+        let signature = isa
+            .signature(raw % 100)
+            .ok_or(ExecutionError::UnknownOpcode(raw % 100))?;
+
+        for i in 0..signature.arity() {
+            let mode = ParameterMode::from_opcode(raw, i as u32)?;
+
+            if !level.allows_mode(mode) {
+                return Err(ExecutionError::UnsupportedMode(mode as u8));
             }
-            &Opcode::Equals(in1, in2, out) => {
-                let val1 = self.get_parameter(in1, 1)?;
-                let val2 = self.get_parameter(in2, 2)?;
-                let result = if val1 == val2 { 1 } else { 0 };
-                self.set_parameter(out, 3, result)?;
-                self.pc_advance(&opcode);
+
+            if signature.roles[i] == isa::Role::Write && mode == ParameterMode::Immediate {
+                return Err(ExecutionError::ImmediateModeWrite);
             }
-            &Opcode::Halt => self.halted = true,
-        };
+        }
+
+        let pc = self.pc;
+
+        match isa.execute(self, raw)? {
+            isa::Control::Next => self.pc_advance(signature.arity()),
+            isa::Control::Jump(target) => self.pc = target,
+            isa::Control::Halt => self.halted = true,
+        }
+
+        self.steps += 1;
+        self.notify(|o| o.on_step(pc, self));
+
+        if self.halted {
+            self.notify(|o| o.on_halt(self));
+        }
 
         Ok(!self.halted)
     }
@@ -287,10 +509,17 @@ impl IntcodeVM {
         Ok(())
     }
 
+    /// Run the program until it halts, using a different instruction set
+    pub fn run_to_end_with<I: isa::InstructionSet<W>>(&mut self, isa: &I) -> Result<()> {
+        while self.step_with(isa)? {}
+
+        Ok(())
+    }
+
     /// Run the program until it halts or another output is generated
     ///
     /// If an output is available immediately, no progress is made in the program.
-    pub fn next_output(&mut self) -> Result<Option<i64>> {
+    pub fn next_output(&mut self) -> Result<Option<W>> {
         let mut keep_going = true;
 
         while self.output.is_empty() && keep_going {
@@ -300,38 +529,136 @@ impl IntcodeVM {
         Ok(self.output.pop_front())
     }
 
+    /// Run the program until a condition is true, checking it before each step
+    ///
+    /// Returns true if the condition was met, or false if the program halted
+    /// first. If the condition is already true, no progress is made.
+    pub fn run_until<F: FnMut(&Self) -> bool>(&mut self, mut done: F) -> Result<bool> {
+        while !done(self) {
+            if !self.step()? {
+                return Ok(done(self));
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Run the program until the PC reaches an address
+    ///
+    /// Returns false if the program halted first. If the PC is already there,
+    /// no progress is made.
+    pub fn run_to_pc(&mut self, address: usize) -> Result<bool> {
+        self.run_until(|vm| vm.pc == address)
+    }
+
+    /// Run the program until the value at an address is different
+    ///
+    /// Returns false if the program halted first. Only plain memory is watched,
+    /// as with `peek`, so writes to a device mapped over the address don't count.
+    pub fn run_until_memory_changes(&mut self, address: usize) -> Result<bool> {
+        let before = self.peek(address)?;
+
+        self.run_until(|vm| vm.peek(address).ok().as_ref() != Some(&before))
+    }
+
     /// Check if the VM has halted
     pub fn halted(&self) -> bool {
         self.halted
     }
 
-    fn pc_advance(&mut self, opcode: &Opcode) {
-        use Opcode::*;
+    /// Tell every observer about an event
+    pub(crate) fn notify<F: FnMut(&mut dyn observer::Observer<W>)>(&self, mut event: F) {
+        for observer in self.observers.iter() {
+            event(&mut *observer.0.lock().unwrap());
+        }
+    }
 
-        let advance = match opcode {
-            Add(..) => 4,
-            Multiply(..) => 4,
-            Input(..) => 2,
-            Output(..) => 2,
-            JumpIfTrue(..) => 3,
-            JumpIfFalse(..) => 3,
-            LessThan(..) => 4,
-            Equals(..) => 4,
-            Halt => 1,
-        };
+    /// Fail if the VM has been cancelled or gone past any of its limits
+    fn check_limits(&self) -> Result<()> {
+        let limits = &self.config.limits;
+
+        if self.cancel.is_cancelled() {
+            return Err(ExecutionError::Cancelled);
+        }
+
+        if limits.steps.is_some_and(|steps| self.steps >= steps) {
+            return Err(ExecutionError::StepLimit);
+        }
+
+        if limits
+            .deadline
+            .is_some_and(|deadline| std::time::Instant::now() >= deadline)
+        {
+            return Err(ExecutionError::DeadlineExceeded);
+        }
 
-        self.pc += advance;
+        Ok(())
+    }
+
+    /// Move the PC past an instruction with the given number of parameters
+    fn pc_advance(&mut self, arity: usize) {
+        self.pc += 1 + arity;
+    }
+
+    fn relative_address(&self, offset: W) -> Result<usize> {
+        let address = self
+            .relative_base
+            .clone()
+            .checked_add(offset)
+            .ok_or(ExecutionError::InvalidAddress)?;
+
+        Self::value_to_index(address)
     }
 
-    fn value_to_index(value: i64) -> Result<usize> {
+    fn device_at(&self, index: usize) -> Option<&device::Mapping<W>> {
+        self.devices
+            .iter()
+            .rev()
+            .find(|mapping| mapping.range.contains(&index))
+    }
+
+    fn value_to_index(value: W) -> Result<usize> {
         use std::convert::TryInto;
 
-        value.try_into().map_err(|_| ExecutionError::InvalidAddress)
+        value
+            .to_i64()
+            .and_then(|value| value.try_into().ok())
+            .ok_or(ExecutionError::InvalidAddress)
     }
 }
 
-impl Iterator for IntcodeVM {
-    type Item = i64;
+impl IntcodeVM {
+    /// Take a single step, running a whole function call as one step
+    ///
+    /// A call is the usual sequence of pushing a return address onto the
+    /// relative-base stack and jumping, as recognised by `cfg::Cfg`. Starting
+    /// at either instruction, this runs until the call returns to the same
+    /// stack frame, so recursive calls are stepped over too. Anything else is
+    /// a plain `step`.
+    pub fn step_over(&mut self) -> Result<bool> {
+        let push_next = disasm::Instruction::decode(&self.memory, self.pc)
+            .ok()
+            .map(|instruction| instruction.next())
+            .filter(|next| *next == self.pc + 4);
+
+        let ret = cfg::call_return(&self.memory, self.pc)
+            .or_else(|| push_next.and_then(|next| cfg::call_return(&self.memory, next)));
+
+        let ret = match ret {
+            Some(ret) => ret,
+            None => return self.step(),
+        };
+
+        let base = self.relative_base;
+        self.step()?;
+        self.run_until(|vm| vm.pc == ret && vm.relative_base == base)?;
+
+        Ok(!self.halted)
+    }
+}
+
+impl<W: Word> Iterator for Machine<W> {
+    type Item = W;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.output.pop_front()
@@ -396,9 +723,15 @@ mod test {
             Ok(Add(Immediate, Immediate, Position))
         );
 
-        assert_eq!(Opcode::from_raw(2101), Err(ExecutionError::UnknownMode(2)));
+        assert_eq!(Opcode::from_raw(9), Ok(AdjustRelativeBase(Position)));
+        assert_eq!(
+            Opcode::from_raw(22201),
+            Ok(Add(Relative, Relative, Relative))
+        );
+
+        assert_eq!(Opcode::from_raw(3101), Err(ExecutionError::UnknownMode(3)));
 
-        for opcode in 9..98 {
+        for opcode in 10..98 {
             assert_eq!(
                 Opcode::from_raw(opcode),
                 Err(ExecutionError::UnknownOpcode(opcode))
@@ -658,4 +991,82 @@ mod test {
             assert_eq!(big_9.pop_output(), Some(1001));
         }
     }
+
+    #[test]
+    fn relative_base() {
+        // Quine from day 9
+        let quine = vec![
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        let mut memory = quine.clone();
+        memory.resize(102, 0);
+
+        let mut vm = IntcodeVM::new(memory);
+        vm.run_to_end().unwrap();
+        assert_eq!(vm.iter_output().copied().collect::<Vec<_>>(), quine);
+        assert_eq!(vm.relative_base(), 16);
+
+        // Write through the relative base
+        let mut vm = IntcodeVM::new(vec![109, 8, 21101, 2, 3, -1, 99, 0]);
+        vm.run_to_end().unwrap();
+        assert_eq!(vm.get_memory(7), Ok(5));
+    }
+
+    #[test]
+    fn run_until() {
+        let mut vm = IntcodeVM::new(vec![
+            1001, 14, 1, 14, // Count in slot 14
+            4, 14, // Output it
+            1008, 14, 5, 15, // Check for 5
+            1006, 15, 0, // Loop
+            99, 0, 0,
+        ]);
+
+        assert_eq!(vm.run_until(|vm| vm.get_memory(14) == Ok(3)), Ok(true));
+        assert_eq!(vm.pc(), 4);
+        assert_eq!(vm.iter_output().count(), 2);
+
+        assert_eq!(vm.run_to_pc(10), Ok(true));
+        assert_eq!(vm.run_to_pc(10), Ok(true));
+        assert_eq!(vm.pop_output(), Some(1));
+
+        assert_eq!(vm.run_until_memory_changes(14), Ok(true));
+        assert_eq!(vm.get_memory(14), Ok(4));
+        assert_eq!(vm.pc(), 4);
+
+        assert_eq!(vm.run_to_pc(100), Ok(false));
+        assert!(vm.halted());
+    }
+
+    #[test]
+    fn step_over() {
+        let mut memory = vec![
+            109, 100, // Set up the stack
+            21101, 7, 0, 1, // Argument
+            21101, 13, 0, 0, // Return address
+            1105, 1, 16, // Call
+            4, 50, 99, // Output the result
+            1202, 1, 2, 50, // Double the argument
+            2105, 1, 0, // Return
+        ];
+        memory.resize(102, 0);
+
+        // From the return address push
+        let mut vm = IntcodeVM::new(memory.clone());
+        assert_eq!(vm.step_over(), Ok(true));
+        assert_eq!(vm.step_over(), Ok(true));
+        assert_eq!(vm.pc(), 6);
+        assert_eq!(vm.step_over(), Ok(true));
+        assert_eq!(vm.pc(), 13);
+        assert_eq!(vm.get_memory(50), Ok(14));
+
+        // From the jump itself
+        let mut vm = IntcodeVM::new(memory);
+        vm.run_to_pc(10).unwrap();
+        assert_eq!(vm.step_over(), Ok(true));
+        assert_eq!(vm.pc(), 13);
+        assert_eq!(vm.step_over(), Ok(true));
+        assert_eq!(vm.pop_output(), Some(14));
+        assert_eq!(vm.step_over(), Ok(false));
+    }
 }
@@ -0,0 +1,396 @@
+use crate::{ExecutionError, IntcodeVM, Result};
+use std::collections::VecDeque;
+
+/// The value a machine reads when its input queue is empty
+pub const NO_PACKET: i64 = -1;
+
+/// How many empty reads in a row a machine needs before it counts as idle
+///
+/// The reads only count as in a row if the machine does nothing but poll in
+/// between; see `Network` for details.
+pub const IDLE_THRESHOLD: usize = 2;
+
+/// A single packet sent between machines on a `Network`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Packet {
+    pub dest: i64,
+    pub x: i64,
+    pub y: i64,
+}
+
+/// A special network participant that gets to act when every machine is idle
+pub trait Nat {
+    /// Handle a packet sent to the NAT's address
+    ///
+    /// Returns false if the network should stop running.
+    fn receive(&mut self, packet: Packet) -> bool;
+
+    /// Called whenever every machine on the network is idle
+    ///
+    /// Returns a packet to inject into the network, or `None` if the network
+    /// should stop running.
+    fn idle(&mut self) -> Option<Packet>;
+}
+
+/// The reason `Network::run` stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkStop {
+    /// Every machine on the network has halted
+    Halted,
+    /// Every machine is idle and there is no NAT to wake them up
+    Idle,
+    /// The NAT asked the network to stop
+    Stopped,
+}
+
+/// A set of addressed VMs exchanging packets with each other
+///
+/// Each machine is given its address as its first input. Every three outputs
+/// from a machine form a packet `(dest, x, y)`, and `x` and `y` are delivered
+/// to the input queue of the machine at `dest`. A machine that reads from an
+/// empty input queue gets `NO_PACKET` instead of blocking.
+///
+/// A machine counts as idle once it has made `IDLE_THRESHOLD` empty reads in a
+/// row, and stays idle for as long as it keeps polling. Sending or receiving a
+/// packet wakes it up, and so does running for longer than the gap between its
+/// last two empty reads without reading again, since then it's busy computing.
+pub struct Network {
+    machines: Vec<IntcodeVM>,
+    polling: Vec<Polling>,
+    nat: Option<(i64, Box<dyn Nat>)>,
+    outbox: VecDeque<Packet>,
+}
+
+/// How a machine has been reading from its empty input queue
+#[derive(Debug, Clone, Copy, Default)]
+struct Polling {
+    /// Empty reads in a row, with only polling in between
+    empty_reads: usize,
+    /// Steps since the last empty read
+    since_read: usize,
+    /// Steps between the last two empty reads
+    period: usize,
+}
+
+impl Polling {
+    fn empty_read(&mut self) {
+        if self.empty_reads > 0 {
+            self.period = self.since_read;
+        }
+
+        self.empty_reads += 1;
+        self.since_read = 0;
+    }
+
+    fn other_step(&mut self) {
+        self.since_read += 1;
+
+        // Two empty reads give the polling period, and going past it means work
+        if self.empty_reads >= 2 && self.since_read > self.period {
+            self.empty_reads = 0;
+        }
+    }
+}
+
+impl Network {
+    /// Create a network of `size` copies of a VM, addressed from 0
+    ///
+    /// The copies are detached (see `IntcodeVM::detached`), so they don't
+    /// share devices, observers or a cancellation token with `base_vm`.
+    pub fn new(base_vm: &IntcodeVM, size: usize) -> Self {
+        Self::from_machines(vec![base_vm.detached(); size])
+    }
+
+    /// Create a network from existing VMs, addressed by their index
+    pub fn from_machines(mut machines: Vec<IntcodeVM>) -> Self {
+        for (address, vm) in machines.iter_mut().enumerate() {
+            vm.push_input(address as i64);
+        }
+
+        Self {
+            polling: vec![Polling::default(); machines.len()],
+            machines,
+            nat: None,
+            outbox: VecDeque::new(),
+        }
+    }
+
+    /// Attach a NAT at the given address
+    ///
+    /// Packets sent to `address` go to the NAT instead of the outbox.
+    pub fn set_nat<N: Nat + 'static>(&mut self, address: i64, nat: N) {
+        self.nat = Some((address, Box::new(nat)));
+    }
+
+    /// Get the machine with the given address
+    pub fn machine(&self, address: usize) -> Option<&IntcodeVM> {
+        self.machines.get(address)
+    }
+
+    /// Get the number of machines on the network
+    pub fn size(&self) -> usize {
+        self.machines.len()
+    }
+
+    /// Pop a packet that was sent to an address with no machine or NAT on it
+    pub fn pop_packet(&mut self) -> Option<Packet> {
+        self.outbox.pop_front()
+    }
+
+    /// Check if every machine has halted
+    pub fn halted(&self) -> bool {
+        self.machines.iter().all(IntcodeVM::halted)
+    }
+
+    /// Check if every running machine is waiting for packets that aren't coming
+    pub fn idle(&self) -> bool {
+        self.machines
+            .iter()
+            .zip(&self.polling)
+            .all(|(vm, polling)| vm.halted() || polling.empty_reads >= IDLE_THRESHOLD)
+    }
+
+    /// Deliver a packet as if it had been sent by a machine
+    ///
+    /// Returns false if the packet went to the NAT and the NAT asked to stop.
+    pub fn send(&mut self, packet: Packet) -> bool {
+        if let Some((address, nat)) = &mut self.nat {
+            if *address == packet.dest {
+                return nat.receive(packet);
+            }
+        }
+
+        let index = if packet.dest >= 0 {
+            packet.dest as usize
+        } else {
+            self.machines.len()
+        };
+
+        match self.machines.get_mut(index) {
+            Some(vm) => {
+                vm.push_inputs(vec![packet.x, packet.y]);
+                self.polling[index] = Polling::default();
+            }
+            None => self.outbox.push_back(packet),
+        }
+
+        true
+    }
+
+    /// Run every machine for a single instruction
+    ///
+    /// Returns false if the NAT asked to stop.
+    pub fn step(&mut self) -> Result<bool> {
+        for address in 0..self.machines.len() {
+            if let Some(packet) = self.step_machine(address)? {
+                self.polling[address] = Polling::default();
+
+                if !self.send(packet) {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Run the network until it halts, goes idle, or is stopped by the NAT
+    ///
+    /// Whenever the network goes idle, the NAT (if there is one) is asked for
+    /// a packet to wake it back up.
+    pub fn run(&mut self) -> Result<NetworkStop> {
+        loop {
+            if self.halted() {
+                return Ok(NetworkStop::Halted);
+            }
+
+            if self.idle() {
+                let wakeup = match &mut self.nat {
+                    Some((_, nat)) => nat.idle(),
+                    None => return Ok(NetworkStop::Idle),
+                };
+
+                match wakeup {
+                    Some(packet) => {
+                        if !self.send(packet) {
+                            return Ok(NetworkStop::Stopped);
+                        }
+                    }
+                    None => return Ok(NetworkStop::Stopped),
+                }
+            }
+
+            if !self.step()? {
+                return Ok(NetworkStop::Stopped);
+            }
+        }
+    }
+
+    fn step_machine(&mut self, address: usize) -> Result<Option<Packet>> {
+        let vm = &mut self.machines[address];
+
+        if vm.halted() {
+            return Ok(None);
+        }
+
+        match vm.step() {
+            Err(ExecutionError::NeedsInput) => {
+                vm.push_input(NO_PACKET);
+                self.polling[address].empty_read();
+                vm.step()?;
+            }
+            other => {
+                other?;
+                self.polling[address].other_step();
+            }
+        }
+
+        if vm.iter_output().count() < 3 {
+            return Ok(None);
+        }
+
+        // Three outputs are queued, so all three pops succeed
+        Ok(Some(Packet {
+            dest: vm.pop_output().unwrap(),
+            x: vm.pop_output().unwrap(),
+            y: vm.pop_output().unwrap(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Waits for a packet (x, y), then sends (address + 1, x, y + address)
+    const FORWARDER: &[i64] = &[
+        3, 30, // Input address to slot 30
+        3, 31, // Input x to slot 31
+        1008, 31, -1, 33, // Check if x was NO_PACKET
+        1005, 33, 2, // If so, go back and try again
+        3, 32, // Input y to slot 32
+        1, 32, 30, 32, // Add the address to y
+        1001, 30, 1, 33, // Compute the next address
+        4, 33, 4, 31, 4, 32, // Send the packet
+        1105, 1, 2, // Wait for the next packet
+        0, 0, 0, 0,
+    ];
+
+    struct RecordingNat {
+        wakeup: Option<Packet>,
+        received: std::rc::Rc<std::cell::RefCell<Vec<Packet>>>,
+    }
+
+    impl Nat for RecordingNat {
+        fn receive(&mut self, packet: Packet) -> bool {
+            self.received.borrow_mut().push(packet);
+            true
+        }
+
+        fn idle(&mut self) -> Option<Packet> {
+            self.wakeup.take()
+        }
+    }
+
+    #[test]
+    fn idle_without_nat() {
+        let mut network = Network::new(&IntcodeVM::new(FORWARDER), 3);
+
+        assert_eq!(network.run(), Ok(NetworkStop::Idle));
+        assert!(network.idle());
+        assert_eq!(network.pop_packet(), None);
+    }
+
+    #[test]
+    fn forward_to_outbox() {
+        let mut network = Network::new(&IntcodeVM::new(FORWARDER), 3);
+        assert!(network.send(Packet {
+            dest: 0,
+            x: 1,
+            y: 10
+        }));
+
+        assert_eq!(network.run(), Ok(NetworkStop::Idle));
+        assert_eq!(
+            network.pop_packet(),
+            Some(Packet {
+                dest: 3,
+                x: 1,
+                y: 13
+            })
+        );
+        assert_eq!(network.pop_packet(), None);
+    }
+
+    #[test]
+    fn nat_wakes_network() {
+        let received = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+
+        let mut network = Network::new(&IntcodeVM::new(FORWARDER), 3);
+        network.set_nat(
+            3,
+            RecordingNat {
+                wakeup: Some(Packet {
+                    dest: 1,
+                    x: 7,
+                    y: 100,
+                }),
+                received: received.clone(),
+            },
+        );
+
+        assert_eq!(network.run(), Ok(NetworkStop::Stopped));
+        assert_eq!(
+            &*received.borrow(),
+            &[Packet {
+                dest: 3,
+                x: 7,
+                y: 103
+            }]
+        );
+    }
+
+    #[test]
+    fn busy_machine_isnt_idle() {
+        // Poll twice, then count down for a while before sending to the NAT
+        let mut slow = vec![
+            3, 30, // Input address to slot 30
+            3, 31, 3, 31, // Poll twice
+            1001, 32, -1, 32, // Count down
+            1005, 32, 6, // Until zero
+            104, 255, 104, 1, 104, 2, // Send (1, 2) to the NAT
+            3, 31, 1105, 1, 19, // Poll forever
+        ];
+        slow.resize(33, 0);
+        slow[32] = 50;
+
+        let received = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut network =
+            Network::from_machines(vec![IntcodeVM::new(slow), IntcodeVM::new(FORWARDER)]);
+        network.set_nat(
+            255,
+            RecordingNat {
+                wakeup: None,
+                received: received.clone(),
+            },
+        );
+
+        assert_eq!(network.run(), Ok(NetworkStop::Stopped));
+        assert_eq!(
+            &*received.borrow(),
+            &[Packet {
+                dest: 255,
+                x: 1,
+                y: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn halted_network() {
+        let mut network = Network::new(&IntcodeVM::new(vec![3, 3, 99, 0]), 4);
+
+        assert_eq!(network.run(), Ok(NetworkStop::Halted));
+        assert_eq!(network.machine(2).unwrap().memory(), &[3, 3, 99, 2]);
+    }
+}
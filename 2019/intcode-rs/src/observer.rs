@@ -0,0 +1,133 @@
+use crate::{Machine, Word};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Callbacks for watching a VM run, attached with `Machine::observe`
+///
+/// Every callback does nothing by default, so implement only the ones you
+/// need. Memory reads and writes are the ones made by instruction parameters,
+/// not calls to `get_memory` and `set_memory` from outside the VM.
+///
+/// The memory, input and output events of an instruction come as it runs,
+/// followed by `on_step` once it has finished. An instruction that fails gets
+/// no `on_step`, so one retried after `NeedsInput` is only reported once.
+pub trait Observer<W: Word = i64> {
+    /// The instruction at `pc` ran, leaving the VM as `vm`
+    fn on_step(&mut self, _pc: usize, _vm: &Machine<W>) {}
+
+    /// An instruction read a value from memory
+    fn on_memory_read(&mut self, _address: usize, _value: &W) {}
+
+    /// An instruction wrote a value to memory
+    fn on_memory_write(&mut self, _address: usize, _value: &W) {}
+
+    /// An input was taken from the input queue
+    fn on_input(&mut self, _value: &W) {}
+
+    /// An output was added to the output queue
+    fn on_output(&mut self, _value: &W) {}
+
+    /// The program halted
+    fn on_halt(&mut self, _vm: &Machine<W>) {}
+}
+
+/// An observer attached to a VM
+#[derive(Clone)]
+pub(crate) struct Attached<W: Word>(pub Arc<Mutex<dyn Observer<W> + Send>>);
+
+impl<W: Word> fmt::Debug for Attached<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Attached").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::IntcodeVM;
+
+    #[derive(Debug, Default)]
+    struct Log(Vec<String>);
+
+    impl Observer for Log {
+        fn on_step(&mut self, pc: usize, _vm: &IntcodeVM) {
+            self.0.push(format!("step {}", pc));
+        }
+
+        fn on_memory_read(&mut self, address: usize, value: &i64) {
+            self.0.push(format!("read {} = {}", address, value));
+        }
+
+        fn on_memory_write(&mut self, address: usize, value: &i64) {
+            self.0.push(format!("write {} = {}", address, value));
+        }
+
+        fn on_input(&mut self, value: &i64) {
+            self.0.push(format!("in {}", value));
+        }
+
+        fn on_output(&mut self, value: &i64) {
+            self.0.push(format!("out {}", value));
+        }
+
+        fn on_halt(&mut self, vm: &IntcodeVM) {
+            self.0.push(format!("halt {}", vm.pc()));
+        }
+    }
+
+    #[test]
+    fn events() {
+        // Double the input
+        let mut vm = IntcodeVM::new(vec![3, 9, 102, 2, 9, 9, 4, 9, 99, 0]);
+        let log = vm.observe(Log::default());
+        assert_eq!(vm.step(), Err(crate::ExecutionError::NeedsInput));
+        vm.push_input(21);
+        vm.run_to_end().unwrap();
+
+        assert_eq!(
+            log.lock().unwrap().0,
+            vec![
+                "in 21",
+                "write 9 = 21",
+                "step 0",
+                "read 9 = 21",
+                "write 9 = 42",
+                "step 2",
+                "read 9 = 42",
+                "out 42",
+                "step 6",
+                "step 8",
+                "halt 8",
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_and_cleared() {
+        #[derive(Default)]
+        struct Steps(usize);
+
+        impl Observer for Steps {
+            fn on_step(&mut self, _pc: usize, _vm: &IntcodeVM) {
+                self.0 += 1;
+            }
+        }
+
+        let mut vm = IntcodeVM::new(vec![104, 1, 104, 2, 99]);
+        let steps = vm.observe(Steps::default());
+        vm.step().unwrap();
+
+        // Clones report to the same observers, detached copies don't
+        let mut clone = vm.clone();
+        clone.step().unwrap();
+        assert_eq!(steps.lock().unwrap().0, 2);
+
+        let mut detached = vm.detached();
+        detached.run_to_end().unwrap();
+        assert_eq!(steps.lock().unwrap().0, 2);
+
+        vm.clear_observers();
+        vm.run_to_end().unwrap();
+        assert_eq!(steps.lock().unwrap().0, 2);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::cfg::{self, Cfg};
+use crate::disasm::{mnemonic, Instruction};
+use crate::{IntcodeVM, Opcode, ParameterMode, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+
+/// The width and height of each memory cell in a heatmap, in pixels
+pub const HEATMAP_CELL: usize = 4;
+
+/// A VM that counts how often each instruction runs and each cell is used
+///
+/// Executions are counted per address and per opcode kind, and memory reads
+/// and writes are counted per address. Only parameters read through a
+/// pointer count as reads; immediate parameters are part of the instruction.
+///
+/// Calls and returns following the relative-base convention (see `cfg::Cfg`)
+/// are tracked as well, so executions can be attributed to a call stack for
+/// flame graphs.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    vm: IntcodeVM,
+    /// The program image as it was at the start, for finding basic blocks
+    image: Vec<i64>,
+    executions: BTreeMap<usize, u64>,
+    opcodes: BTreeMap<&'static str, u64>,
+    reads: BTreeMap<usize, u64>,
+    writes: BTreeMap<usize, u64>,
+    /// The entry addresses of the functions currently being called, outermost first
+    calls: Vec<usize>,
+    /// Executions per call stack, then per address and mnemonic
+    stacks: BTreeMap<Vec<usize>, BTreeMap<(usize, &'static str), u64>>,
+}
+
+impl Profiler {
+    /// Start profiling a VM
+    pub fn new(vm: IntcodeVM) -> Self {
+        Self {
+            image: vm.memory().to_vec(),
+            vm,
+            executions: BTreeMap::new(),
+            opcodes: BTreeMap::new(),
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+            calls: Vec::new(),
+            stacks: BTreeMap::new(),
+        }
+    }
+
+    /// Get the VM being profiled
+    pub fn vm(&self) -> &IntcodeVM {
+        &self.vm
+    }
+
+    /// Get the VM being profiled, to add input or take output
+    pub fn vm_mut(&mut self) -> &mut IntcodeVM {
+        &mut self.vm
+    }
+
+    /// Get the total number of instructions executed
+    pub fn steps(&self) -> u64 {
+        self.executions.values().sum()
+    }
+
+    /// Get the number of times the instruction at an address was executed
+    pub fn executions(&self, address: usize) -> u64 {
+        self.executions.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Get the number of instructions executed of each kind, by mnemonic
+    pub fn opcodes(&self) -> &BTreeMap<&'static str, u64> {
+        &self.opcodes
+    }
+
+    /// Get the number of times a memory cell was read through a parameter
+    pub fn reads(&self, address: usize) -> u64 {
+        self.reads.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Get the number of times a memory cell was written
+    pub fn writes(&self, address: usize) -> u64 {
+        self.writes.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Take a single step through the program, counting what it does
+    ///
+    /// Behaves exactly like `IntcodeVM::step`.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.vm.halted() {
+            return self.vm.step();
+        }
+
+        let pc = self.vm.pc();
+        let instruction = Instruction::decode(self.vm.memory(), pc)?;
+        let name = mnemonic(&instruction.opcode);
+        let written = instruction.write_parameter();
+
+        let mut reads = Vec::new();
+        let mut write = None;
+
+        for (i, (mode, value)) in instruction
+            .opcode
+            .modes()
+            .into_iter()
+            .zip(instruction.parameters.iter())
+            .enumerate()
+        {
+            let address = match mode {
+                ParameterMode::Position => *value,
+                ParameterMode::Relative => match self.vm.relative_base().checked_add(*value) {
+                    Some(address) => address,
+                    None => continue,
+                },
+                ParameterMode::Immediate => continue,
+            };
+
+            if address < 0 {
+                continue;
+            } else if Some(i) == written {
+                write = Some(address as usize);
+            } else {
+                reads.push(address as usize);
+            }
+        }
+
+        let running = self.vm.step()?;
+
+        *self.executions.entry(pc).or_insert(0) += 1;
+        *self.opcodes.entry(name).or_insert(0) += 1;
+        let stack = match self.stacks.get_mut(self.calls.as_slice()) {
+            Some(stack) => stack,
+            None => self.stacks.entry(self.calls.clone()).or_default(),
+        };
+        *stack.entry((pc, name)).or_insert(0) += 1;
+
+        for address in reads {
+            *self.reads.entry(address).or_insert(0) += 1;
+        }
+
+        if let Some(address) = write {
+            *self.writes.entry(address).or_insert(0) += 1;
+        }
+
+        let jumped = self.vm.pc() != instruction.next();
+
+        match instruction.opcode {
+            Opcode::JumpIfTrue(_, ParameterMode::Relative)
+            | Opcode::JumpIfFalse(_, ParameterMode::Relative)
+                if jumped =>
+            {
+                self.calls.pop();
+            }
+            Opcode::JumpIfTrue(_, ParameterMode::Immediate)
+            | Opcode::JumpIfFalse(_, ParameterMode::Immediate)
+                if jumped && cfg::is_call(self.vm.memory(), &instruction) =>
+            {
+                self.calls.push(self.vm.pc());
+            }
+            _ => {}
+        }
+
+        Ok(running)
+    }
+
+    /// Run the program until it halts
+    pub fn run_to_end(&mut self) -> Result<()> {
+        while self.step()? {}
+
+        Ok(())
+    }
+
+    /// Write a readable report of the profile
+    ///
+    /// Lists the `top` most executed instructions and basic blocks, then the
+    /// mix of instructions executed. Basic blocks are found from the program
+    /// image as it was when profiling started, and are counted by how often
+    /// their first instruction ran.
+    pub fn report(&self, top: usize) -> String {
+        let steps = self.steps();
+        let mut out = String::new();
+
+        writeln!(out, "{} instructions executed", steps).unwrap();
+
+        writeln!(out, "\nHottest instructions:").unwrap();
+        for (address, count) in hottest(self.executions.iter().map(|(a, c)| (*a, *c)), top) {
+            let text = match Instruction::decode(self.vm.memory(), address) {
+                Ok(instruction) => instruction.to_string(),
+                Err(_) => "?".to_string(),
+            };
+            writeln!(out, "{:>10}  {:>6}: {}", count, address, text).unwrap();
+        }
+
+        writeln!(out, "\nHottest blocks:").unwrap();
+        let cfg = Cfg::new(&self.image);
+        let blocks = cfg
+            .blocks()
+            .keys()
+            .map(|start| (*start, self.executions(*start)))
+            .filter(|(_, count)| *count > 0);
+        for (start, count) in hottest(blocks, top) {
+            let end = cfg.block(start).unwrap().end();
+            writeln!(out, "{:>10}  {:>6}..{}", count, start, end).unwrap();
+        }
+
+        writeln!(out, "\nInstruction mix:").unwrap();
+        for (name, count) in hottest(self.opcodes.iter().map(|(n, c)| (*n, *c)), usize::MAX) {
+            let percent = 100.0 * count as f64 / steps as f64;
+            writeln!(out, "{:>10}  {:>5.1}%  {}", count, percent, name).unwrap();
+        }
+
+        out
+    }
+
+    /// Write the profile in collapsed-stack format, for flame graph tools
+    ///
+    /// Each line is the call stack, from `main` through each function called
+    /// (named `func_N` after its address), then the instruction as `mnemonic@address`,
+    /// followed by the number of times it was executed with that stack.
+    pub fn collapsed(&self) -> String {
+        let mut out = String::new();
+
+        for (calls, stack) in self.stacks.iter() {
+            for ((address, name), count) in stack.iter() {
+                out += "main";
+                for entry in calls.iter() {
+                    write!(out, ";func_{}", entry).unwrap();
+                }
+                writeln!(out, ";{}@{} {}", name, address, count).unwrap();
+            }
+        }
+
+        out
+    }
+
+    /// Write a heatmap of memory use as a binary PPM image
+    ///
+    /// Each memory cell is a square in a grid `columns` cells wide, starting
+    /// with address 0 in the top left. Writes are shown in red, reads in green
+    /// and executions in blue, each on a log scale up to the busiest cell, so
+    /// code shows up blue, busy variables yellow and untouched memory black.
+    /// Every cell of an executed instruction counts as executed.
+    ///
+    /// Below the grid, a legend shows the red, green and blue scales from one
+    /// use up to the maximum, and the maximums are noted in the header.
+    ///
+    /// Fails with `InvalidInput` if `columns` is 0.
+    pub fn write_heatmap<W: io::Write>(&self, out: &mut W, columns: usize) -> io::Result<()> {
+        if columns == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a heatmap needs at least one column",
+            ));
+        }
+
+        let size = self.vm.memory().len();
+        let rows = size.div_ceil(columns);
+        let width = columns * HEATMAP_CELL;
+        let height = (rows + 4) * HEATMAP_CELL;
+
+        let mut executions = vec![0; size];
+        for (pc, count) in self.executions.iter() {
+            let end = match Instruction::decode(self.vm.memory(), *pc) {
+                Ok(instruction) => instruction.next(),
+                Err(_) => pc + 1,
+            };
+
+            for cell in executions[*pc..end.min(size)].iter_mut() {
+                *cell += count;
+            }
+        }
+
+        let counts = |map: &BTreeMap<usize, u64>| -> Vec<u64> {
+            (0..size)
+                .map(|a| map.get(&a).copied().unwrap_or(0))
+                .collect()
+        };
+        let channels = [counts(&self.writes), counts(&self.reads), executions];
+        let maxes: Vec<u64> = channels
+            .iter()
+            .map(|c| c.iter().copied().max().unwrap_or(0))
+            .collect();
+
+        let mut pixels = vec![0; width * height * 3];
+        let mut fill = |x: usize, y: usize, w: usize, h: usize, color: [u8; 3]| {
+            for row in y..y + h {
+                for col in x..x + w {
+                    let i = (row * width + col) * 3;
+                    pixels[i..i + 3].copy_from_slice(&color);
+                }
+            }
+        };
+
+        for address in 0..size {
+            let mut color = [0; 3];
+            for (c, channel) in channels.iter().enumerate() {
+                color[c] = intensity(channel[address], maxes[c]);
+            }
+
+            let (x, y) = (address % columns, address / columns);
+            fill(
+                x * HEATMAP_CELL,
+                y * HEATMAP_CELL,
+                HEATMAP_CELL,
+                HEATMAP_CELL,
+                color,
+            );
+        }
+
+        for c in 0..3 {
+            for x in 0..width {
+                let mut color = [0; 3];
+                color[c] = 64 + (191 * x / (width - 1).max(1)) as u8;
+                fill(x, (rows + 1 + c) * HEATMAP_CELL, 1, HEATMAP_CELL, color);
+            }
+        }
+
+        writeln!(out, "P6")?;
+        writeln!(
+            out,
+            "# Memory heatmap: {} cells, {} per row, starting at address 0 in the top left",
+            size, columns
+        )?;
+        writeln!(
+            out,
+            "# Red: writes (max {}), green: reads (max {}), blue: executions (max {})",
+            maxes[0], maxes[1], maxes[2]
+        )?;
+        writeln!(
+            out,
+            "# Legend below the grid, top to bottom: red, green, blue, from 1 to max"
+        )?;
+        writeln!(out, "{} {}\n255", width, height)?;
+        out.write_all(&pixels)
+    }
+}
+
+/// Scale a count to a color intensity, on a log scale up to `max`
+///
+/// Anything used at all is kept visible against the black background.
+fn intensity(count: u64, max: u64) -> u8 {
+    if count == 0 {
+        0
+    } else if max <= 1 {
+        255
+    } else {
+        64 + (191.0 * (count as f64).ln() / (max as f64).ln()).round() as u8
+    }
+}
+
+/// Get the `top` entries with the highest counts, highest first
+///
+/// Ties are kept in their original order.
+fn hottest<K>(counts: impl Iterator<Item = (K, u64)>, top: usize) -> Vec<(K, u64)> {
+    let mut counts: Vec<_> = counts.collect();
+    counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+    counts.truncate(top);
+    counts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ExecutionError;
+
+    #[test]
+    fn counts() {
+        // Count down from 3, outputting each number
+        let mut memory = vec![4, 20, 1001, 20, -1, 20, 1005, 20, 0, 99];
+        memory.resize(21, 0);
+        memory[20] = 3;
+
+        let mut profiler = Profiler::new(IntcodeVM::new(memory));
+        profiler.run_to_end().unwrap();
+
+        assert_eq!(profiler.steps(), 10);
+        assert_eq!(profiler.executions(0), 3);
+        assert_eq!(profiler.executions(9), 1);
+        assert_eq!(profiler.reads(20), 9);
+        assert_eq!(profiler.writes(20), 3);
+        assert_eq!(profiler.opcodes()["add"], 3);
+
+        assert_eq!(
+            profiler.report(2),
+            "10 instructions executed\n\
+             \n\
+             Hottest instructions:\n         \
+                      3       0: out [20]\n         \
+                      3       2: add [20], -1, [20]\n\
+             \n\
+             Hottest blocks:\n         \
+                      3       0..9\n         \
+                      1       9..10\n\
+             \n\
+             Instruction mix:\n         \
+                      3   30.0%  add\n         \
+                      3   30.0%  jt\n         \
+                      3   30.0%  out\n         \
+                      1   10.0%  halt\n"
+        );
+
+        // Read past the end of the address space
+        let mut profiler = Profiler::new(IntcodeVM::new(vec![109, i64::MAX, 204, 10, 99]));
+        assert_eq!(profiler.run_to_end(), Err(ExecutionError::InvalidAddress));
+    }
+
+    #[test]
+    fn heatmap() {
+        // Count down from 3
+        let memory = vec![1001, 9, -1, 9, 1005, 9, 0, 99, 0, 3];
+
+        let mut profiler = Profiler::new(IntcodeVM::new(memory));
+        profiler.run_to_end().unwrap();
+
+        let mut image = Vec::new();
+        profiler.write_heatmap(&mut image, 4).unwrap();
+
+        let header = "P6\n\
+                      # Memory heatmap: 10 cells, 4 per row, starting at address 0 in the top left\n\
+                      # Red: writes (max 3), green: reads (max 6), blue: executions (max 3)\n\
+                      # Legend below the grid, top to bottom: red, green, blue, from 1 to max\n\
+                      16 28\n\
+                      255\n";
+        assert!(image.starts_with(header.as_bytes()));
+
+        let pixels = &image[header.len()..];
+        assert_eq!(pixels.len(), 16 * 28 * 3);
+        let pixel = |address: usize| {
+            let (x, y) = (address % 4 * HEATMAP_CELL, address / 4 * HEATMAP_CELL);
+            let i = (y * 16 + x) * 3;
+            &pixels[i..i + 3]
+        };
+
+        // Code, the counter, and unused memory
+        assert_eq!(pixel(0), &[0, 0, 255]);
+        assert_eq!(pixel(9), &[255, 255, 0]);
+        assert_eq!(pixel(8), &[0, 0, 0]);
+
+        // The start and end of the blue legend bar
+        let legend = ((3 + 3) * HEATMAP_CELL * 16) * 3;
+        assert_eq!(&pixels[legend..legend + 3], &[0, 0, 64]);
+        assert_eq!(&pixels[legend + 15 * 3..legend + 16 * 3], &[0, 0, 255]);
+
+        let error = profiler.write_heatmap(&mut Vec::new(), 0).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn collapsed() {
+        let mut memory = vec![
+            109, 100, // Set up the stack
+            21101, 9, 0, 0, // Return address
+            1105, 1, 13, // Call
+            99, 0, 0, 0, // Padding
+            104, 7, // Output
+            2105, 1, 0, // Return
+        ];
+        memory.resize(101, 0);
+
+        let mut profiler = Profiler::new(IntcodeVM::new(memory));
+        profiler.run_to_end().unwrap();
+
+        assert_eq!(
+            profiler.collapsed(),
+            "main;arb@0 1\n\
+             main;add@2 1\n\
+             main;jt@6 1\n\
+             main;halt@9 1\n\
+             main;func_13;out@13 1\n\
+             main;func_13;jt@15 1\n"
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::disasm::Instruction;
+use crate::{ExecutionError, IntcodeVM, ParameterMode};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// What a protected memory region can be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    /// Can be executed but not written, like code
+    ReadOnly,
+    /// Can be written but not executed, like variables
+    NoExecute,
+    /// Can only be read, like constant tables
+    DataOnly,
+}
+
+impl Protection {
+    fn writable(self) -> bool {
+        self == Protection::NoExecute
+    }
+
+    fn executable(self) -> bool {
+        self == Protection::ReadOnly
+    }
+}
+
+/// An error from a program run with memory protection
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtectionError {
+    /// The program failed in the same way a plain VM would
+    Execution(ExecutionError),
+    /// The instruction at `pc` tried to write to a protected `address`
+    WriteFault { pc: usize, address: usize },
+    /// The instruction at `pc` is in, or runs into, a non-executable `address`
+    ExecuteFault { pc: usize, address: usize },
+}
+
+impl From<ExecutionError> for ProtectionError {
+    fn from(error: ExecutionError) -> Self {
+        ProtectionError::Execution(error)
+    }
+}
+
+type Result<T> = std::result::Result<T, ProtectionError>;
+
+/// A write to a memory cell that was later executed as part of an instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelfModification {
+    /// The address of the instruction that did the write
+    pub writer: usize,
+    /// The cell that was written
+    pub address: usize,
+    /// The address of the instruction that executed the written cell
+    pub executed: usize,
+}
+
+/// A VM with protected memory regions, which also reports self-modifying code
+///
+/// Any instruction that would write to or execute memory against its region's
+/// protection faults before it runs, leaving the VM unchanged. Every cell of
+/// an instruction, including its parameters, counts as executed.
+///
+/// Separately, every write to a cell that is later executed is recorded as a
+/// `SelfModification`, whether or not any regions are protected. Each write is
+/// only reported the first time the cell is executed afterwards.
+#[derive(Debug, Clone)]
+pub struct MemoryGuard {
+    vm: IntcodeVM,
+    regions: Vec<(Range<usize>, Protection)>,
+    /// The address of the last instruction to write to each cell
+    writes: BTreeMap<usize, usize>,
+    self_modifications: Vec<SelfModification>,
+}
+
+impl MemoryGuard {
+    /// Start guarding a VM, with no regions protected
+    pub fn new(vm: IntcodeVM) -> Self {
+        Self {
+            vm,
+            regions: Vec::new(),
+            writes: BTreeMap::new(),
+            self_modifications: Vec::new(),
+        }
+    }
+
+    /// Protect a range of addresses
+    ///
+    /// Later regions take priority where they overlap earlier ones.
+    pub fn protect(&mut self, range: Range<usize>, protection: Protection) {
+        self.regions.push((range, protection));
+    }
+
+    /// Get the protection of an address, if it's in a protected region
+    pub fn protection(&self, address: usize) -> Option<Protection> {
+        self.regions
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(_, protection)| *protection)
+    }
+
+    /// Get the VM being guarded
+    pub fn vm(&self) -> &IntcodeVM {
+        &self.vm
+    }
+
+    /// Get the VM being guarded, to add input or take output
+    pub fn vm_mut(&mut self) -> &mut IntcodeVM {
+        &mut self.vm
+    }
+
+    /// Stop guarding the VM and get it back
+    pub fn into_inner(self) -> IntcodeVM {
+        self.vm
+    }
+
+    /// Get every self-modification seen so far
+    pub fn self_modifications(&self) -> &[SelfModification] {
+        &self.self_modifications
+    }
+
+    /// Take a single step through the program
+    ///
+    /// Behaves exactly like `IntcodeVM::step`, unless the step would fault.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.vm.halted() {
+            return Ok(self.vm.step()?);
+        }
+
+        let pc = self.vm.pc();
+        let instruction = Instruction::decode(self.vm.memory(), pc)?;
+
+        for address in pc..instruction.next() {
+            if self.protection(address).is_some_and(|p| !p.executable()) {
+                return Err(ProtectionError::ExecuteFault { pc, address });
+            }
+        }
+
+        let written = self.write_address(&instruction);
+
+        if let Some(address) = written {
+            if self.protection(address).is_some_and(|p| !p.writable()) {
+                return Err(ProtectionError::WriteFault { pc, address });
+            }
+        }
+
+        let running = self.vm.step()?;
+
+        for address in pc..instruction.next() {
+            if let Some(writer) = self.writes.remove(&address) {
+                self.self_modifications.push(SelfModification {
+                    writer,
+                    address,
+                    executed: pc,
+                });
+            }
+        }
+
+        if let Some(address) = written {
+            self.writes.insert(address, pc);
+        }
+
+        Ok(running)
+    }
+
+    /// Run the program until it halts
+    pub fn run_to_end(&mut self) -> Result<()> {
+        while self.step()? {}
+
+        Ok(())
+    }
+
+    /// Find the address an instruction will write to
+    ///
+    /// Returns `None` if it doesn't write, or if the write is going to fail anyway.
+    fn write_address(&self, instruction: &Instruction) -> Option<usize> {
+        let parameter = instruction.write_parameter()?;
+        let value = instruction.parameters[parameter];
+
+        let address = match instruction.opcode.modes()[parameter] {
+            ParameterMode::Position => value,
+            ParameterMode::Relative => self.vm.relative_base().checked_add(value)?,
+            ParameterMode::Immediate => return None,
+        };
+
+        if address >= 0 {
+            Some(address as usize)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn faults() {
+        // Write to cell 9, then jump to the halt
+        let program = vec![1101, 2, 3, 9, 1105, 1, 7, 99, 0, 0];
+
+        let mut guard = MemoryGuard::new(IntcodeVM::new(program.clone()));
+        guard.protect(0..8, Protection::ReadOnly);
+        guard.protect(8..10, Protection::DataOnly);
+        assert_eq!(
+            guard.run_to_end(),
+            Err(ProtectionError::WriteFault { pc: 0, address: 9 })
+        );
+        assert_eq!(guard.vm().get_memory(9), Ok(0));
+
+        let mut guard = MemoryGuard::new(IntcodeVM::new(program.clone()));
+        guard.protect(0..7, Protection::ReadOnly);
+        guard.protect(7..10, Protection::NoExecute);
+        assert_eq!(
+            guard.run_to_end(),
+            Err(ProtectionError::ExecuteFault { pc: 7, address: 7 })
+        );
+        assert_eq!(guard.vm().get_memory(9), Ok(5));
+
+        let mut guard = MemoryGuard::new(IntcodeVM::new(program));
+        guard.protect(0..8, Protection::ReadOnly);
+        guard.protect(8..10, Protection::NoExecute);
+        assert_eq!(guard.run_to_end(), Ok(()));
+
+        // Write past the end of the address space
+        let mut guard = MemoryGuard::new(IntcodeVM::new(vec![109, i64::MAX, 203, 10, 99]));
+        guard.vm_mut().push_input(1);
+        assert_eq!(
+            guard.run_to_end(),
+            Err(ProtectionError::Execution(ExecutionError::InvalidAddress))
+        );
+    }
+
+    #[test]
+    fn self_modification() {
+        // Patch the output at 8 to be immediate, then patch the halt at 10
+        let program = vec![1101, 100, 4, 8, 1101, 0, 99, 10, 4, 5, 0];
+
+        let mut guard = MemoryGuard::new(IntcodeVM::new(program));
+        assert_eq!(guard.run_to_end(), Ok(()));
+        assert_eq!(guard.vm_mut().pop_output(), Some(5));
+        assert_eq!(
+            guard.self_modifications(),
+            &[
+                SelfModification {
+                    writer: 0,
+                    address: 8,
+                    executed: 8,
+                },
+                SelfModification {
+                    writer: 4,
+                    address: 10,
+                    executed: 10,
+                },
+            ]
+        );
+    }
+}
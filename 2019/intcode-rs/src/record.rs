@@ -0,0 +1,408 @@
+use crate::disasm::Instruction;
+use crate::observer::Observer;
+use crate::{ExecutionError, IntcodeVM};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// Which way a value went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// A value going into or out of a VM, and the step that moved it
+///
+/// Steps count from 0, as given by `IntcodeVM::steps` before the instruction
+/// runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub step: u64,
+    pub direction: Direction,
+    pub value: i64,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Input => "input",
+            Direction::Output => "output",
+        };
+
+        write!(f, "{} {} at step {}", direction, self.value, self.step)
+    }
+}
+
+/// Every input and output of a session, in order
+///
+/// Saved as text, one event per line: the step, `in` or `out`, and the value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub events: Vec<Event>,
+}
+
+impl Recording {
+    /// Get the recorded inputs, in order
+    pub fn inputs(&self) -> impl Iterator<Item = i64> + '_ {
+        self.events
+            .iter()
+            .filter(|e| e.direction == Direction::Input)
+            .map(|e| e.value)
+    }
+
+    /// Write the recording out as text
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for event in self.events.iter() {
+            let direction = match event.direction {
+                Direction::Input => "in",
+                Direction::Output => "out",
+            };
+
+            writeln!(out, "{} {} {}", event.step, direction, event.value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a recording written by `write`
+    pub fn read<R: BufRead>(input: R) -> io::Result<Self> {
+        let mut events = Vec::new();
+
+        for line in input.lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            events.push(parse_event(&line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid event {:?}", line),
+                )
+            })?);
+        }
+
+        Ok(Self { events })
+    }
+
+    /// Save the recording to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write(io::BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Load a recording from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read(io::BufReader::new(std::fs::File::open(path)?))
+    }
+}
+
+fn parse_event(line: &str) -> Option<Event> {
+    let mut words = line.split_whitespace();
+
+    let step = words.next()?.parse().ok()?;
+    let direction = match words.next()? {
+        "in" => Direction::Input,
+        "out" => Direction::Output,
+        _ => return None,
+    };
+    let value = words.next()?.parse().ok()?;
+
+    if words.next().is_some() {
+        return None;
+    }
+
+    Some(Event {
+        step,
+        direction,
+        value,
+    })
+}
+
+/// An observer that records every input and output of a VM
+///
+/// Attach it with `IntcodeVM::observe`, run the session as usual, then take
+/// the `Recording` to save it.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder {
+    /// Events of the instruction running now, which get their step when it finishes
+    pending: Vec<(Direction, i64)>,
+    recording: Recording,
+}
+
+impl Recorder {
+    /// Create a recorder with nothing recorded
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get everything recorded so far
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+}
+
+impl Observer for Recorder {
+    fn on_step(&mut self, _pc: usize, vm: &IntcodeVM) {
+        let step = vm.steps() - 1;
+
+        self.recording
+            .events
+            .extend(self.pending.drain(..).map(|(direction, value)| Event {
+                step,
+                direction,
+                value,
+            }));
+    }
+
+    fn on_input(&mut self, value: &i64) {
+        self.pending.push((Direction::Input, *value));
+    }
+
+    fn on_output(&mut self, value: &i64) {
+        self.pending.push((Direction::Output, *value));
+    }
+}
+
+/// Where a replayed session stopped matching its recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// The step where the difference showed up
+    pub step: u64,
+    /// The disassembly of the instruction run at that step, with its address
+    pub instruction: String,
+    /// What the recording says should have happened next, if anything
+    pub expected: Option<Event>,
+    /// What actually happened, or `None` if the program halted or needed
+    /// more input than was recorded
+    pub actual: Option<Event>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "diverged at step {} ({}): ", self.step, self.instruction)?;
+
+        match self.expected {
+            Some(event) => write!(f, "expected {}", event)?,
+            None => write!(f, "expected nothing more")?,
+        }
+
+        match self.actual {
+            Some(event) => write!(f, ", got {}", event),
+            None => write!(f, ", but the program stopped"),
+        }
+    }
+}
+
+/// An error from replaying a recording
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    /// The program failed in the same way a plain VM would
+    Execution(ExecutionError),
+    /// The program didn't do what the recording says it did
+    Divergence(Divergence),
+}
+
+impl From<ExecutionError> for ReplayError {
+    fn from(error: ExecutionError) -> Self {
+        ReplayError::Execution(error)
+    }
+}
+
+type Result<T> = std::result::Result<T, ReplayError>;
+
+/// Run a VM on the inputs of a recording, checking it behaves the same way
+///
+/// The VM should be in the state the recording started from. All recorded
+/// inputs are queued up front, and every input taken and output produced
+/// must match the recording, at the same step. Inputs already queued are
+/// taken first, so they show up as a divergence. Running out of input is a
+/// divergence too. Outputs are left in the VM's output queue.
+pub fn replay(vm: &mut IntcodeVM, recording: &Recording) -> Result<()> {
+    let mut expected = recording.events.iter().copied();
+
+    vm.push_inputs(recording.inputs());
+
+    loop {
+        let step = vm.steps();
+        let instruction = (vm.pc(), Instruction::decode(vm.memory(), vm.pc()));
+        let (next_input, inputs_before, outputs_before) =
+            (vm.input.front().copied(), vm.input.len(), vm.output.len());
+
+        let running = match vm.step() {
+            Err(ExecutionError::NeedsInput) => {
+                return Err(ReplayError::Divergence(Divergence {
+                    step,
+                    instruction: describe(&instruction),
+                    expected: expected.next(),
+                    actual: None,
+                }))
+            }
+            result => result?,
+        };
+
+        let mut actual = Vec::new();
+        if vm.input.len() < inputs_before {
+            actual.push((Direction::Input, next_input.unwrap()));
+        }
+        if vm.output.len() > outputs_before {
+            actual.push((Direction::Output, *vm.output.back().unwrap()));
+        }
+
+        for (direction, value) in actual {
+            let actual = Event {
+                step,
+                direction,
+                value,
+            };
+            let next = expected.next();
+
+            if next != Some(actual) {
+                return Err(ReplayError::Divergence(Divergence {
+                    step,
+                    instruction: describe(&instruction),
+                    expected: next,
+                    actual: Some(actual),
+                }));
+            }
+        }
+
+        if !running {
+            if let Some(next) = expected.next() {
+                return Err(ReplayError::Divergence(Divergence {
+                    step,
+                    instruction: describe(&instruction),
+                    expected: Some(next),
+                    actual: None,
+                }));
+            }
+
+            return Ok(());
+        }
+    }
+}
+
+/// Disassemble an instruction decoded before it ran, with its address
+fn describe((address, instruction): &(usize, crate::Result<Instruction>)) -> String {
+    match instruction {
+        Ok(instruction) => format!("{}: {}", address, instruction),
+        Err(_) => format!("{}: invalid", address),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Output each input plus 10, until the input is 0
+    const ADD_TEN: &[i64] = &[
+        3, 15, 1006, 15, 14, 1001, 15, 10, 15, 4, 15, 1105, 1, 0, 99, 0,
+    ];
+
+    fn record(program: &[i64], inputs: &[i64]) -> Recording {
+        let mut vm = IntcodeVM::new(program.to_vec());
+        let recorder = vm.observe(Recorder::new());
+
+        for input in inputs {
+            vm.push_input(*input);
+            while vm.step().is_ok() && !vm.halted() {}
+        }
+
+        let recording = recorder.lock().unwrap().recording().clone();
+        recording
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let recording = record(ADD_TEN, &[1, 5, 0]);
+        assert_eq!(
+            recording.events[..2],
+            [
+                Event {
+                    step: 0,
+                    direction: Direction::Input,
+                    value: 1
+                },
+                Event {
+                    step: 3,
+                    direction: Direction::Output,
+                    value: 11
+                },
+            ]
+        );
+        assert_eq!(recording.events.len(), 5);
+
+        let path = std::env::temp_dir().join(format!("intcode-record-{}", std::process::id()));
+        recording.save(&path).unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("0 in 1\n3 out 11\n5 in 5\n"));
+        let loaded = Recording::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, recording);
+
+        let mut vm = IntcodeVM::new(ADD_TEN.to_vec());
+        assert_eq!(replay(&mut vm, &loaded), Ok(()));
+        assert_eq!(vm.iter_output().copied().collect::<Vec<_>>(), vec![11, 15]);
+    }
+
+    #[test]
+    fn divergence() {
+        let recording = record(ADD_TEN, &[1, 5, 0]);
+
+        // Add 20 instead
+        let mut changed = ADD_TEN.to_vec();
+        changed[7] = 20;
+        let mut vm = IntcodeVM::new(changed);
+
+        let divergence = match replay(&mut vm, &recording) {
+            Err(ReplayError::Divergence(divergence)) => divergence,
+            other => panic!("expected a divergence, got {:?}", other),
+        };
+        assert_eq!(divergence.step, 3);
+        assert_eq!(divergence.instruction, "9: out [15]");
+        assert_eq!(
+            divergence.to_string(),
+            "diverged at step 3 (9: out [15]): expected output 11 at step 3, got output 21 at step 3"
+        );
+
+        // Halt straight away
+        let mut vm = IntcodeVM::new(vec![3, 0, 99]);
+        let divergence = match replay(&mut vm, &recording) {
+            Err(ReplayError::Divergence(divergence)) => divergence,
+            other => panic!("expected a divergence, got {:?}", other),
+        };
+        assert_eq!(divergence.actual, None);
+        assert_eq!(divergence.instruction, "2: halt");
+
+        // Run out of input
+        let mut vm = IntcodeVM::new(ADD_TEN.to_vec());
+        let divergence = match replay(&mut vm, &record(ADD_TEN, &[1, 5])) {
+            Err(ReplayError::Divergence(divergence)) => divergence,
+            other => panic!("expected a divergence, got {:?}", other),
+        };
+        assert_eq!((divergence.expected, divergence.actual), (None, None));
+        assert_eq!(divergence.instruction, "0: in [15]");
+        assert!(divergence.to_string().ends_with("but the program stopped"));
+
+        // Take an input queued before replaying
+        let mut vm = IntcodeVM::new(ADD_TEN.to_vec());
+        vm.push_input(7);
+        let divergence = match replay(&mut vm, &Recording::default()) {
+            Err(ReplayError::Divergence(divergence)) => divergence,
+            other => panic!("expected a divergence, got {:?}", other),
+        };
+        assert_eq!(divergence.expected, None);
+        assert_eq!(
+            divergence.actual,
+            Some(Event {
+                step: 0,
+                direction: Direction::Input,
+                value: 7
+            })
+        );
+
+        assert!(Recording::read("1 sideways 3\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{ExecutionError, IntcodeVM, Result};
+use std::collections::VecDeque;
+
+/// The reason `Scheduler::run` stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchedulerStop {
+    /// Every VM has halted
+    Halted,
+    /// Every VM that hasn't halted is waiting on input that will never come
+    Deadlocked,
+}
+
+/// Runs many VMs in turn on a single thread
+///
+/// Each round, every VM that hasn't halted runs for up to `slice` steps,
+/// stopping early if it needs input. Whenever a VM produces an output, the
+/// routing function is called with the index of the VM and the value. It
+/// returns the index of the VM whose input queue should get the value, or
+/// `None` to send it out of the scheduler (see `pop_output`).
+///
+/// Given the same VMs, slice, and routing, every run is exactly the same,
+/// regardless of the machine or how busy it is.
+pub struct Scheduler<R> {
+    vms: Vec<IntcodeVM>,
+    slice: usize,
+    route: R,
+    output: VecDeque<(usize, i64)>,
+    rounds: usize,
+}
+
+impl<R: FnMut(usize, i64) -> Option<usize>> Scheduler<R> {
+    /// Create a scheduler that gives each VM `slice` steps per round
+    ///
+    /// A slice of 0 is treated as 1.
+    pub fn new(vms: Vec<IntcodeVM>, slice: usize, route: R) -> Self {
+        Self {
+            vms,
+            slice: slice.max(1),
+            route,
+            output: VecDeque::new(),
+            rounds: 0,
+        }
+    }
+
+    /// Get all of the VMs
+    pub fn vms(&self) -> &[IntcodeVM] {
+        &self.vms
+    }
+
+    /// Get a VM so that it can be given input
+    pub fn vm_mut(&mut self, index: usize) -> Option<&mut IntcodeVM> {
+        self.vms.get_mut(index)
+    }
+
+    /// Pop an output that was routed out of the scheduler, along with the
+    /// index of the VM that produced it
+    pub fn pop_output(&mut self) -> Option<(usize, i64)> {
+        self.output.pop_front()
+    }
+
+    /// Get the number of rounds run so far
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Check if every VM has halted
+    pub fn halted(&self) -> bool {
+        self.vms.iter().all(IntcodeVM::halted)
+    }
+
+    /// Give every VM a turn
+    ///
+    /// Returns the total number of steps taken. If none were taken and not
+    /// every VM has halted, the VMs are deadlocked.
+    pub fn round(&mut self) -> Result<usize> {
+        let mut total = 0;
+
+        for index in 0..self.vms.len() {
+            for _ in 0..self.slice {
+                if self.vms[index].halted() {
+                    break;
+                }
+
+                match self.vms[index].step() {
+                    Ok(_) => total += 1,
+                    Err(ExecutionError::NeedsInput) => break,
+                    Err(e) => return Err(e),
+                }
+
+                while let Some(value) = self.vms[index].pop_output() {
+                    match (self.route)(index, value).and_then(|d| self.vms.get_mut(d)) {
+                        Some(dest) => dest.push_input(value),
+                        None => self.output.push_back((index, value)),
+                    }
+                }
+            }
+        }
+
+        self.rounds += 1;
+
+        Ok(total)
+    }
+
+    /// Run rounds until every VM halts or they deadlock
+    pub fn run(&mut self) -> Result<SchedulerStop> {
+        loop {
+            if self.halted() {
+                return Ok(SchedulerStop::Halted);
+            }
+
+            if self.round()? == 0 {
+                return Ok(SchedulerStop::Deadlocked);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FEEDBACK_AMPLIFIER: &[i64] = &[
+        3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+        1005, 28, 6, 99, 0, 0, 5,
+    ];
+
+    fn amplifiers(settings: &[i64]) -> Vec<IntcodeVM> {
+        let mut vms: Vec<_> = settings
+            .iter()
+            .map(|setting| {
+                let mut vm = IntcodeVM::new(FEEDBACK_AMPLIFIER);
+                vm.push_input(*setting);
+                vm
+            })
+            .collect();
+
+        vms[0].push_input(0);
+        vms
+    }
+
+    fn run_scheduled(settings: &[i64], slice: usize) -> i64 {
+        let mut scheduler = Scheduler::new(amplifiers(settings), slice, |from, _| {
+            if from == 4 {
+                None
+            } else {
+                Some(from + 1)
+            }
+        });
+
+        // Feed the last amplifier back into the first by hand
+        let mut last = None;
+
+        loop {
+            let stop = scheduler.run().unwrap();
+
+            while let Some((_, value)) = scheduler.pop_output() {
+                scheduler.vm_mut(0).unwrap().push_input(value);
+                last = Some(value);
+            }
+
+            if stop == SchedulerStop::Halted {
+                return last.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn feedback_loop() {
+        for slice in 0..10 {
+            assert_eq!(run_scheduled(&[9, 8, 7, 6, 5], slice), 139629729);
+        }
+    }
+
+    #[test]
+    fn matches_threaded() {
+        use crate::threaded::Supervisor;
+        use std::sync::mpsc::channel;
+
+        for settings in &[[9, 8, 7, 6, 5], [9, 7, 8, 5, 6], [5, 6, 7, 8, 9]] {
+            let mut supervisor = Supervisor::new();
+            let (senders, receivers): (Vec<_>, Vec<_>) = (0..5).map(|_| channel()).unzip();
+
+            for (i, (vm, rx)) in amplifiers(settings).into_iter().zip(receivers).enumerate() {
+                supervisor.spawn(vm, rx, senders[(i + 1) % 5].clone());
+            }
+
+            drop(senders);
+            let report = supervisor.join();
+
+            assert_eq!(
+                report.finished[0].input.try_recv(),
+                Ok(run_scheduled(settings, 3))
+            );
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        // Each VM outputs its input plus one, forever
+        let program = vec![3, 11, 1001, 11, 1, 11, 4, 11, 1105, 1, 0, 0];
+
+        let trace = |slice| {
+            let mut vms = vec![IntcodeVM::new(program.clone()); 3];
+            vms[0].push_input(0);
+            vms[1].push_input(100);
+
+            let mut scheduler = Scheduler::new(vms, slice, |from, value| {
+                if value % 10 == 0 {
+                    None
+                } else {
+                    Some((from + 1) % 3)
+                }
+            });
+
+            assert_eq!(scheduler.run(), Ok(SchedulerStop::Deadlocked));
+
+            let rounds = scheduler.rounds();
+            let outputs: Vec<_> = std::iter::from_fn(|| scheduler.pop_output()).collect();
+            (rounds, outputs)
+        };
+
+        // The interleaving depends on the slice, but never on anything else
+        assert_eq!(trace(1), trace(1));
+        assert_eq!(trace(1), (30, vec![(1, 110), (0, 10)]));
+        assert_eq!(trace(100), trace(100));
+        assert_eq!(trace(100), (5, vec![(0, 10), (1, 110)]));
+    }
+
+    #[test]
+    fn error() {
+        let vms = vec![IntcodeVM::new(vec![3, 0, 99]), IntcodeVM::new(vec![42])];
+        let mut scheduler = Scheduler::new(vms, 1, |_, _| None);
+
+        assert_eq!(scheduler.run(), Err(ExecutionError::UnknownOpcode(42)));
+    }
+}
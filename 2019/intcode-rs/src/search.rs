@@ -0,0 +1,223 @@
+use crate::IntcodeVM;
+use rayon::prelude::*;
+
+/// One set of changes to try on a copy of the base VM
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Candidate {
+    /// Memory cells to overwrite before running, as `(address, value)`
+    pub patches: Vec<(usize, i64)>,
+    /// Values to add to the input queue before running
+    pub inputs: Vec<i64>,
+}
+
+impl Candidate {
+    /// Create a candidate that only patches memory
+    pub fn patches<P: Into<Vec<(usize, i64)>>>(patches: P) -> Self {
+        Self {
+            patches: patches.into(),
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Create a candidate that only provides input
+    pub fn inputs<I: Into<Vec<i64>>>(inputs: I) -> Self {
+        Self {
+            patches: Vec::new(),
+            inputs: inputs.into(),
+        }
+    }
+
+    /// Run a copy of the base VM with this candidate's changes
+    ///
+    /// The copy is detached (see `IntcodeVM::detached`), so it doesn't touch
+    /// the base VM's devices or observers, or stop when it's cancelled. It
+    /// runs with the base VM's limits, but counts steps from the start of the
+    /// candidate, so a step limit bounds every candidate separately.
+    ///
+    /// Returns `None` if a patch is out of bounds or the program fails,
+    /// including by going past a limit.
+    pub fn run(&self, base_vm: &IntcodeVM) -> Option<IntcodeVM> {
+        let mut vm = base_vm.detached();
+        vm.config.limits.steps = vm
+            .config
+            .limits
+            .steps
+            .map(|steps| steps.saturating_add(vm.steps()));
+
+        for (address, value) in self.patches.iter() {
+            vm.set_memory(*address, *value).ok()?;
+        }
+
+        vm.push_inputs(self.inputs.iter().copied());
+        vm.run_to_end().ok()?;
+
+        Some(vm)
+    }
+}
+
+/// Search candidates in parallel for the first one whose finished VM matches
+///
+/// "First" is in the order the candidates are generated, so the result is the
+/// same as a sequential search. Candidates after a match may be skipped.
+/// Candidates that fail to run never match, so if some might never halt, give
+/// the base VM a step limit or deadline (see `Candidate::run`).
+pub fn find_first<C, P>(base_vm: &IntcodeVM, candidates: C, predicate: P) -> Option<Candidate>
+where
+    C: IntoParallelIterator<Item = Candidate>,
+    P: Fn(&IntcodeVM) -> bool + Sync,
+{
+    candidates
+        .into_par_iter()
+        .find_first(|candidate| matches(base_vm, candidate, &predicate))
+}
+
+/// Search candidates in parallel for any one whose finished VM matches
+///
+/// Stops as soon as any match is found, so this can be faster than
+/// `find_first`, but which match is returned is not deterministic.
+pub fn find_any<C, P>(base_vm: &IntcodeVM, candidates: C, predicate: P) -> Option<Candidate>
+where
+    C: IntoParallelIterator<Item = Candidate>,
+    P: Fn(&IntcodeVM) -> bool + Sync,
+{
+    candidates
+        .into_par_iter()
+        .find_any(|candidate| matches(base_vm, candidate, &predicate))
+}
+
+/// Search candidates in parallel for every one whose finished VM matches
+///
+/// Matches are returned in the order the candidates are generated.
+pub fn find_all<C, P>(base_vm: &IntcodeVM, candidates: C, predicate: P) -> Vec<Candidate>
+where
+    C: IntoParallelIterator<Item = Candidate>,
+    P: Fn(&IntcodeVM) -> bool + Sync,
+{
+    candidates
+        .into_par_iter()
+        .filter(|candidate| matches(base_vm, candidate, &predicate))
+        .collect()
+}
+
+fn matches<P: Fn(&IntcodeVM) -> bool>(
+    base_vm: &IntcodeVM,
+    candidate: &Candidate,
+    predicate: &P,
+) -> bool {
+    candidate
+        .run(base_vm)
+        .map(|vm| predicate(&vm))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn noun_verb() -> impl ParallelIterator<Item = Candidate> {
+        (0..100 * 100)
+            .into_par_iter()
+            .map(|i| Candidate::patches(vec![(1, i / 100), (2, i % 100)]))
+    }
+
+    #[test]
+    fn first_and_all() {
+        // Multiplies the noun and verb, then adds 7
+        let vm = IntcodeVM::new(vec![1102, 0, 0, 0, 1001, 0, 7, 0, 99]);
+        let target = |vm: &IntcodeVM| vm.get_memory(0) == Ok(19 * 37 + 7);
+
+        assert_eq!(
+            find_first(&vm, noun_verb(), target),
+            Some(Candidate::patches(vec![(1, 19), (2, 37)]))
+        );
+        assert_eq!(
+            find_all(&vm, noun_verb(), target),
+            vec![
+                Candidate::patches(vec![(1, 19), (2, 37)]),
+                Candidate::patches(vec![(1, 37), (2, 19)]),
+            ]
+        );
+
+        let any = find_any(&vm, noun_verb(), target).unwrap();
+        assert!(target(&any.run(&vm).unwrap()));
+
+        assert_eq!(
+            find_first(&vm, noun_verb(), |vm| vm.get_memory(0) == Ok(-1)),
+            None
+        );
+    }
+
+    #[test]
+    fn inputs() {
+        // Doubles its input
+        let vm = IntcodeVM::new(vec![3, 0, 102, 2, 0, 0, 4, 0, 99]);
+
+        let found = find_first(
+            &vm,
+            (-100..100)
+                .into_par_iter()
+                .map(|i| Candidate::inputs(vec![i])),
+            |vm| vm.clone().pop_output() == Some(42),
+        );
+
+        assert_eq!(found, Some(Candidate::inputs(vec![21])));
+    }
+
+    #[test]
+    fn limits() {
+        #[derive(Default)]
+        struct Steps(u64);
+
+        impl crate::observer::Observer for Steps {
+            fn on_step(&mut self, _pc: usize, _vm: &IntcodeVM) {
+                self.0 += 1;
+            }
+        }
+
+        // Loop forever if the flag at 9 is set, otherwise store 42 at 10
+        let program = vec![1005, 9, 0, 1101, 20, 22, 10, 99, 0, 0, 0];
+        let config = crate::config::VmConfig {
+            limits: crate::config::Limits {
+                steps: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut vm = IntcodeVM::with_config(program, config).unwrap();
+        let steps = vm.observe(Steps::default());
+
+        // Use up most of the step limit before searching
+        vm.set_memory(9, 1).unwrap();
+        for _ in 0..90 {
+            vm.step().unwrap();
+        }
+
+        // The first two never halt, but the last still gets its own 100 steps
+        let candidates = vec![
+            Candidate::patches(vec![(9, 1)]),
+            Candidate::patches(vec![(9, 0), (0, 1105)]),
+            Candidate::patches(vec![(9, 0)]),
+        ];
+        assert_eq!(
+            find_all(&vm, candidates, |vm| vm.get_memory(10) == Ok(42)),
+            vec![Candidate::patches(vec![(9, 0)])]
+        );
+        assert_eq!(steps.lock().unwrap().0, 90);
+    }
+
+    #[test]
+    fn failures_never_match() {
+        let vm = IntcodeVM::new(vec![3, 0, 99]);
+
+        let candidates = vec![
+            Candidate::patches(vec![(5, 1)]),
+            Candidate::default(),
+            Candidate::inputs(vec![1]),
+        ];
+
+        assert_eq!(
+            find_all(&vm, candidates, |_| true),
+            vec![Candidate::inputs(vec![1])]
+        );
+    }
+}
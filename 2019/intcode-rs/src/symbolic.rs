@@ -0,0 +1,682 @@
+use crate::{ExecutionError, IntcodeVM, Opcode, ParameterMode};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+/// The value of a memory cell, possibly depending on symbols
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Const(i64),
+    Symbol(String),
+    /// The `n`th value read from input
+    Input(usize),
+    /// A cell read through a pointer that isn't known yet
+    Load(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Multiply(Box<Expr>, Box<Expr>),
+    LessThan(Box<Expr>, Box<Expr>),
+    Equals(Box<Expr>, Box<Expr>),
+}
+
+/// A linear combination of symbols: `constant + sum(coefficient * symbol)`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Linear {
+    pub constant: i64,
+    pub terms: BTreeMap<String, i64>,
+}
+
+/// An error from running a program symbolically
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolicError {
+    /// The program failed in the same way a concrete VM would
+    Execution(ExecutionError),
+    /// The instruction at `pc` depends on a symbol
+    SymbolicOpcode { pc: usize },
+    /// The instruction at `pc` writes through a pointer that depends on a symbol
+    SymbolicAddress { pc: usize },
+    /// The instruction at `pc` jumps based on a symbol
+    SymbolicBranch { pc: usize },
+    /// The instruction at `pc` moves the relative base by a symbolic amount
+    SymbolicRelativeBase { pc: usize },
+}
+
+impl From<ExecutionError> for SymbolicError {
+    fn from(error: ExecutionError) -> Self {
+        SymbolicError::Execution(error)
+    }
+}
+
+type Result<T> = std::result::Result<T, SymbolicError>;
+
+impl Expr {
+    /// Get the value if it doesn't depend on any symbols
+    pub fn as_const(&self) -> Option<i64> {
+        match self {
+            Expr::Const(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Add two expressions, folding them into a linear form if possible
+    ///
+    /// Returns `None` if the folded form overflows.
+    pub fn sum(a: Expr, b: Expr) -> Option<Expr> {
+        match (a.linear(), b.linear()) {
+            (Some(a), Some(b)) => Some(a.plus(&b)?.into()),
+            _ => Some(Expr::Add(Box::new(a), Box::new(b))),
+        }
+    }
+
+    /// Multiply two expressions, folding them into a linear form if possible
+    ///
+    /// Returns `None` if the folded form overflows.
+    pub fn product(a: Expr, b: Expr) -> Option<Expr> {
+        match (a.linear(), b.linear()) {
+            (Some(ref l), Some(ref r)) if l.terms.is_empty() => Some(r.times(l.constant)?.into()),
+            (Some(ref l), Some(ref r)) if r.terms.is_empty() => Some(l.times(r.constant)?.into()),
+            _ => Some(Expr::Multiply(Box::new(a), Box::new(b))),
+        }
+    }
+
+    /// Compare two expressions, folding them into a constant if possible
+    pub fn less_than(a: Expr, b: Expr) -> Expr {
+        match (a.as_const(), b.as_const()) {
+            (Some(l), Some(r)) => Expr::Const(if l < r { 1 } else { 0 }),
+            _ if a == b => Expr::Const(0),
+            _ => Expr::LessThan(Box::new(a), Box::new(b)),
+        }
+    }
+
+    /// Check two expressions for equality, folding them into a constant if possible
+    pub fn equals(a: Expr, b: Expr) -> Expr {
+        match (a.as_const(), b.as_const()) {
+            (Some(l), Some(r)) => Expr::Const(if l == r { 1 } else { 0 }),
+            _ if a == b => Expr::Const(1),
+            _ => Expr::Equals(Box::new(a), Box::new(b)),
+        }
+    }
+
+    /// Get the linear form of the expression, if it has one
+    ///
+    /// Inputs are treated as symbols named `input0`, `input1`, and so on. A
+    /// form whose constant or coefficients would overflow counts as having
+    /// none.
+    pub fn linear(&self) -> Option<Linear> {
+        match self {
+            Expr::Const(value) => Some(Linear {
+                constant: *value,
+                terms: BTreeMap::new(),
+            }),
+            Expr::Symbol(name) => Some(Linear::symbol(name.clone())),
+            Expr::Input(n) => Some(Linear::symbol(format!("input{}", n))),
+            Expr::Add(a, b) => a.linear()?.plus(&b.linear()?),
+            Expr::Multiply(a, b) => {
+                let (a, b) = (a.linear()?, b.linear()?);
+
+                if a.terms.is_empty() {
+                    b.times(a.constant)
+                } else if b.terms.is_empty() {
+                    a.times(b.constant)
+                } else {
+                    None
+                }
+            }
+            Expr::Load(..) | Expr::LessThan(..) | Expr::Equals(..) => None,
+        }
+    }
+
+    /// Rewrite the expression in linear form, if it has one
+    pub fn simplify(&self) -> Expr {
+        match self.linear() {
+            Some(linear) => linear.into(),
+            None => self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Const(value) => write!(f, "{}", value),
+            Expr::Symbol(name) => write!(f, "{}", name),
+            Expr::Input(n) => write!(f, "input{}", n),
+            Expr::Load(address) => write!(f, "[{}]", address),
+            Expr::Add(a, b) => write!(f, "({} + {})", a, b),
+            Expr::Multiply(a, b) => write!(f, "({} * {})", a, b),
+            Expr::LessThan(a, b) => write!(f, "({} < {})", a, b),
+            Expr::Equals(a, b) => write!(f, "({} == {})", a, b),
+        }
+    }
+}
+
+impl Linear {
+    fn symbol(name: String) -> Self {
+        let mut terms = BTreeMap::new();
+        terms.insert(name, 1);
+
+        Self { constant: 0, terms }
+    }
+
+    fn plus(&self, other: &Linear) -> Option<Linear> {
+        let mut sum = self.clone();
+        sum.constant = sum.constant.checked_add(other.constant)?;
+
+        for (name, coefficient) in other.terms.iter() {
+            let term = sum.terms.entry(name.clone()).or_insert(0);
+            *term = term.checked_add(*coefficient)?;
+        }
+
+        sum.terms.retain(|_, coefficient| *coefficient != 0);
+        Some(sum)
+    }
+
+    fn times(&self, factor: i64) -> Option<Linear> {
+        let mut terms = BTreeMap::new();
+
+        for (name, coefficient) in self.terms.iter() {
+            let coefficient = coefficient.checked_mul(factor)?;
+
+            if coefficient != 0 {
+                terms.insert(name.clone(), coefficient);
+            }
+        }
+
+        Some(Linear {
+            constant: self.constant.checked_mul(factor)?,
+            terms,
+        })
+    }
+
+    /// Evaluate the form with the given symbol values
+    ///
+    /// Returns `None` if any symbol is missing, or if the result overflows.
+    pub fn eval(&self, values: &BTreeMap<String, i64>) -> Option<i64> {
+        let mut total = self.constant;
+
+        for (name, coefficient) in self.terms.iter() {
+            total = total.checked_add(coefficient.checked_mul(*values.get(name)?)?)?;
+        }
+
+        Some(total)
+    }
+
+    /// Find symbol values within the given ranges that make the form equal `target`
+    ///
+    /// Every symbol but the last (by name) is tried in order, and the last is
+    /// solved for directly, so this takes time proportional to the product of
+    /// all but one of the ranges. The first solution found is returned, with
+    /// earlier symbols as small as possible.
+    pub fn solve(
+        &self,
+        target: i64,
+        ranges: &BTreeMap<String, Range<i64>>,
+    ) -> Option<BTreeMap<String, i64>> {
+        let terms: Vec<_> = self.terms.iter().collect();
+        let mut values = BTreeMap::new();
+
+        let remaining = target.checked_sub(self.constant)?;
+
+        if Self::solve_from(&terms, remaining, ranges, &mut values) {
+            Some(values)
+        } else {
+            None
+        }
+    }
+
+    fn solve_from(
+        terms: &[(&String, &i64)],
+        remaining: i64,
+        ranges: &BTreeMap<String, Range<i64>>,
+        values: &mut BTreeMap<String, i64>,
+    ) -> bool {
+        match terms {
+            [] => remaining == 0,
+            [(name, &coefficient)] => {
+                let value = match remaining.checked_div(coefficient) {
+                    Some(value) => value,
+                    None => return false,
+                };
+
+                match ranges.get(*name) {
+                    Some(range) if remaining % coefficient == 0 && range.contains(&value) => {
+                        values.insert(name.to_string(), value);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            [(name, &coefficient), rest @ ..] => {
+                for value in ranges.get(*name).cloned().unwrap_or(0..0) {
+                    values.insert(name.to_string(), value);
+
+                    let solved = coefficient
+                        .checked_mul(value)
+                        .and_then(|product| remaining.checked_sub(product))
+                        .is_some_and(|remaining| Self::solve_from(rest, remaining, ranges, values));
+
+                    if solved {
+                        return true;
+                    }
+                }
+
+                values.remove(*name);
+                false
+            }
+        }
+    }
+}
+
+impl From<Linear> for Expr {
+    fn from(linear: Linear) -> Self {
+        let mut expr: Option<Expr> = None;
+
+        for (name, coefficient) in linear.terms {
+            let term = if coefficient == 1 {
+                Expr::Symbol(name)
+            } else {
+                Expr::Multiply(
+                    Box::new(Expr::Const(coefficient)),
+                    Box::new(Expr::Symbol(name)),
+                )
+            };
+
+            expr = Some(match expr {
+                Some(expr) => Expr::Add(Box::new(expr), Box::new(term)),
+                None => term,
+            });
+        }
+
+        match expr {
+            None => Expr::Const(linear.constant),
+            Some(expr) if linear.constant == 0 => expr,
+            Some(expr) => Expr::Add(Box::new(expr), Box::new(Expr::Const(linear.constant))),
+        }
+    }
+}
+
+impl fmt::Display for Linear {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Expr::from(self.clone()))
+    }
+}
+
+/// A VM that runs straight-line code on expressions instead of numbers
+///
+/// Any cell can be marked as a symbol before running. Instructions, pointers
+/// that are written through, and jump conditions must not depend on symbols,
+/// but everything else can, so after running, each cell holds its value as a
+/// function of the symbols.
+#[derive(Debug, Clone)]
+pub struct SymbolicVM {
+    memory: Vec<Expr>,
+    pc: usize,
+    relative_base: i64,
+    halted: bool,
+    inputs: usize,
+    output: Vec<Expr>,
+}
+
+impl SymbolicVM {
+    /// Create a symbolic VM with the same memory as a concrete VM
+    pub fn new(vm: &IntcodeVM) -> Self {
+        Self {
+            memory: vm.memory().iter().map(|v| Expr::Const(*v)).collect(),
+            pc: 0,
+            relative_base: 0,
+            halted: false,
+            inputs: 0,
+            output: Vec::new(),
+        }
+    }
+
+    /// Replace the value of a cell with a symbol
+    pub fn set_symbol(&mut self, index: usize, name: &str) -> Result<()> {
+        let cell = self
+            .memory
+            .get_mut(index)
+            .ok_or(ExecutionError::InvalidAddress)?;
+
+        *cell = Expr::Symbol(name.to_string());
+        Ok(())
+    }
+
+    /// Get the value of memory at a given index
+    pub fn get_memory(&self, index: usize) -> Option<&Expr> {
+        self.memory.get(index)
+    }
+
+    /// Get the entire memory as a slice
+    pub fn memory(&self) -> &[Expr] {
+        &self.memory
+    }
+
+    /// Get every output, in order
+    pub fn outputs(&self) -> &[Expr] {
+        &self.output
+    }
+
+    /// Check if the VM has halted
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Run the program until it halts
+    pub fn run_to_end(&mut self) -> Result<()> {
+        while self.step()? {}
+
+        Ok(())
+    }
+
+    /// Take a single step through the program
+    ///
+    /// Each input instruction reads a new `Expr::Input` instead of a value.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.halted {
+            return Err(ExecutionError::AlreadyHalted.into());
+        }
+
+        let raw = self.concrete(self.pc, ExecutionError::InvalidPC)?;
+        let opcode = Opcode::from_raw(raw.ok_or(SymbolicError::SymbolicOpcode { pc: self.pc })?)?;
+
+        let overflow = ExecutionError::Overflow { pc: self.pc };
+
+        match opcode {
+            Opcode::Add(in1, in2, out) => {
+                let result =
+                    Expr::sum(self.parameter(in1, 1)?, self.parameter(in2, 2)?).ok_or(overflow)?;
+                self.set_parameter(out, 3, result)?;
+            }
+            Opcode::Multiply(in1, in2, out) => {
+                let result = Expr::product(self.parameter(in1, 1)?, self.parameter(in2, 2)?)
+                    .ok_or(overflow)?;
+                self.set_parameter(out, 3, result)?;
+            }
+            Opcode::Input(out) => {
+                self.set_parameter(out, 1, Expr::Input(self.inputs))?;
+                self.inputs += 1;
+            }
+            Opcode::Output(in1) => {
+                let value = self.parameter(in1, 1)?;
+                self.output.push(value);
+            }
+            Opcode::JumpIfTrue(in1, in2) | Opcode::JumpIfFalse(in1, in2) => {
+                let jump_if = matches!(opcode, Opcode::JumpIfTrue(..));
+                let branch = SymbolicError::SymbolicBranch { pc: self.pc };
+
+                let val = self.parameter(in1, 1)?.as_const().ok_or(branch.clone())?;
+                let new_loc = self.parameter(in2, 2)?.as_const().ok_or(branch)?;
+
+                if (val != 0) == jump_if {
+                    self.pc = to_index(new_loc)?;
+                    return Ok(true);
+                }
+            }
+            Opcode::LessThan(in1, in2, out) => {
+                let result = Expr::less_than(self.parameter(in1, 1)?, self.parameter(in2, 2)?);
+                self.set_parameter(out, 3, result)?;
+            }
+            Opcode::Equals(in1, in2, out) => {
+                let result = Expr::equals(self.parameter(in1, 1)?, self.parameter(in2, 2)?);
+                self.set_parameter(out, 3, result)?;
+            }
+            Opcode::AdjustRelativeBase(in1) => {
+                let offset = self
+                    .parameter(in1, 1)?
+                    .as_const()
+                    .ok_or(SymbolicError::SymbolicRelativeBase { pc: self.pc })?;
+                self.relative_base = self.relative_base.checked_add(offset).ok_or(overflow)?;
+            }
+            Opcode::Halt => self.halted = true,
+        }
+
+        if !self.halted {
+            self.pc += opcode.size();
+        }
+
+        Ok(!self.halted)
+    }
+
+    /// Get a cell as a number, or `None` if it depends on a symbol
+    fn concrete(&self, index: usize, missing: ExecutionError) -> Result<Option<i64>> {
+        Ok(self.memory.get(index).ok_or(missing)?.as_const())
+    }
+
+    fn parameter(&self, mode: ParameterMode, offset: usize) -> Result<Expr> {
+        let index = self.pc + offset;
+        let value = self
+            .memory
+            .get(index)
+            .ok_or(ExecutionError::InvalidPC)?
+            .clone();
+
+        match mode {
+            ParameterMode::Immediate => Ok(value),
+            ParameterMode::Position => match value.as_const() {
+                Some(pointer) => Ok(self
+                    .memory
+                    .get(to_index(pointer)?)
+                    .ok_or(ExecutionError::InvalidPC)?
+                    .clone()),
+                None => Ok(Expr::Load(Box::new(value))),
+            },
+            ParameterMode::Relative => match value.as_const() {
+                Some(offset) => Ok(self
+                    .memory
+                    .get(self.relative_index(offset)?)
+                    .ok_or(ExecutionError::InvalidPC)?
+                    .clone()),
+                None => Ok(Expr::Load(Box::new(
+                    Expr::sum(Expr::Const(self.relative_base), value)
+                        .ok_or(ExecutionError::InvalidAddress)?,
+                ))),
+            },
+        }
+    }
+
+    fn set_parameter(&mut self, mode: ParameterMode, offset: usize, value: Expr) -> Result<()> {
+        if mode == ParameterMode::Immediate {
+            return Err(ExecutionError::ImmediateModeWrite.into());
+        }
+
+        let pointer = self
+            .concrete(self.pc + offset, ExecutionError::InvalidPC)?
+            .ok_or(SymbolicError::SymbolicAddress { pc: self.pc })?;
+
+        let index = match mode {
+            ParameterMode::Relative => self.relative_index(pointer)?,
+            _ => to_index(pointer)?,
+        };
+
+        let cell = self
+            .memory
+            .get_mut(index)
+            .ok_or(ExecutionError::InvalidAddress)?;
+
+        *cell = value;
+        Ok(())
+    }
+
+    fn relative_index(&self, offset: i64) -> Result<usize> {
+        to_index(
+            self.relative_base
+                .checked_add(offset)
+                .ok_or(ExecutionError::InvalidAddress)?,
+        )
+    }
+}
+
+fn to_index(value: i64) -> Result<usize> {
+    use std::convert::TryInto;
+
+    value
+        .try_into()
+        .map_err(|_| ExecutionError::InvalidAddress.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn symbol(name: &str) -> Expr {
+        Expr::Symbol(name.to_string())
+    }
+
+    #[test]
+    fn linear_result() {
+        // A cut-down day 2 program: memory[0] = 3 * noun + verb + 7
+        let vm = IntcodeVM::new(vec![
+            1, 0, 0, 3, // Clobber slot 3 through the symbolic pointers
+            1, 1, 2, 3, // slot 3 = noun + verb
+            1002, 1, 2, 0, // slot 0 = noun * 2
+            1, 0, 3, 0, // slot 0 += slot 3
+            1001, 0, 7, 0, // slot 0 += 7
+            99,
+        ]);
+
+        let mut symbolic = SymbolicVM::new(&vm);
+        symbolic.set_symbol(1, "noun").unwrap();
+        symbolic.set_symbol(2, "verb").unwrap();
+        symbolic.run_to_end().unwrap();
+
+        let result = symbolic.get_memory(0).unwrap().linear().unwrap();
+        assert_eq!(result.to_string(), "(((3 * noun) + verb) + 7)");
+
+        let mut ranges = BTreeMap::new();
+        ranges.insert("noun".to_string(), 0..100);
+        ranges.insert("verb".to_string(), 0..100);
+
+        let solution = result.solve(3 * 12 + 2 + 7, &ranges).unwrap();
+        assert_eq!(solution["noun"], 0);
+        assert_eq!(solution["verb"], 38);
+        assert_eq!(result.eval(&solution), Some(45));
+
+        ranges.insert("noun".to_string(), 10..100);
+        let solution = result.solve(3 * 12 + 2 + 7, &ranges).unwrap();
+        assert_eq!((solution["noun"], solution["verb"]), (10, 8));
+
+        assert_eq!(result.solve(1000, &ranges), None);
+
+        // Check the answer against a real run
+        let mut concrete = vm.clone();
+        concrete.set_memory(1, 10).unwrap();
+        concrete.set_memory(2, 8).unwrap();
+        concrete.run_to_end().unwrap();
+        assert_eq!(concrete.get_memory(0), Ok(45));
+    }
+
+    #[test]
+    fn nonlinear() {
+        let mut symbolic = SymbolicVM::new(&IntcodeVM::new(vec![2, 5, 6, 0, 99, 0, 0]));
+        symbolic.set_symbol(5, "a").unwrap();
+        symbolic.set_symbol(6, "b").unwrap();
+        symbolic.run_to_end().unwrap();
+
+        assert_eq!(
+            symbolic.get_memory(0),
+            Some(&Expr::Multiply(
+                Box::new(symbol("a")),
+                Box::new(symbol("b"))
+            ))
+        );
+        assert_eq!(symbolic.get_memory(0).unwrap().linear(), None);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut symbolic = SymbolicVM::new(&IntcodeVM::new(vec![1101, i64::MAX, 1, 0, 99]));
+        assert_eq!(
+            symbolic.run_to_end(),
+            Err(SymbolicError::Execution(ExecutionError::Overflow { pc: 0 }))
+        );
+
+        // Double a symbol past the largest coefficient
+        let mut symbolic = SymbolicVM::new(&IntcodeVM::new(vec![
+            1002,
+            9,
+            i64::MAX,
+            9,
+            1002,
+            9,
+            2,
+            9,
+            99,
+            0,
+        ]));
+        symbolic.set_symbol(9, "a").unwrap();
+        assert_eq!(
+            symbolic.run_to_end(),
+            Err(SymbolicError::Execution(ExecutionError::Overflow { pc: 4 }))
+        );
+
+        let mut symbolic = SymbolicVM::new(&IntcodeVM::new(vec![109, i64::MAX, 204, 1, 99]));
+        assert_eq!(
+            symbolic.run_to_end(),
+            Err(SymbolicError::Execution(ExecutionError::InvalidAddress))
+        );
+
+        let linear = Linear::symbol("a".to_string()).times(i64::MAX).unwrap();
+        let mut values = BTreeMap::new();
+        values.insert("a".to_string(), 2);
+        assert_eq!(linear.eval(&values), None);
+
+        let mut ranges = BTreeMap::new();
+        ranges.insert("a".to_string(), 0..10);
+        assert_eq!(linear.solve(i64::MIN, &ranges), None);
+    }
+
+    #[test]
+    fn inputs_and_outputs() {
+        let mut symbolic = SymbolicVM::new(&IntcodeVM::new(vec![
+            3, 0, 1002, 0, 4, 0, 1008, 0, 8, 1, 4, 0, 4, 1, 99,
+        ]));
+        symbolic.run_to_end().unwrap();
+
+        let quadrupled = Expr::from(Linear::symbol("input0".to_string()).times(4).unwrap());
+        assert_eq!(
+            symbolic.outputs(),
+            &[
+                quadrupled.clone(),
+                Expr::Equals(Box::new(quadrupled), Box::new(Expr::Const(8))),
+            ]
+        );
+    }
+
+    #[test]
+    fn symbolic_errors() {
+        let mut branch = SymbolicVM::new(&IntcodeVM::new(vec![1105, 0, 0, 99]));
+        branch.set_symbol(1, "x").unwrap();
+        assert_eq!(
+            branch.run_to_end(),
+            Err(SymbolicError::SymbolicBranch { pc: 0 })
+        );
+
+        let mut write = SymbolicVM::new(&IntcodeVM::new(vec![1101, 1, 1, 0, 99]));
+        write.set_symbol(3, "x").unwrap();
+        assert_eq!(
+            write.run_to_end(),
+            Err(SymbolicError::SymbolicAddress { pc: 0 })
+        );
+
+        let mut opcode = SymbolicVM::new(&IntcodeVM::new(vec![1101, 1, 1, 0, 99]));
+        opcode.set_symbol(4, "x").unwrap();
+        assert_eq!(
+            opcode.run_to_end(),
+            Err(SymbolicError::SymbolicOpcode { pc: 4 })
+        );
+    }
+
+    #[test]
+    fn relative_base() {
+        // Input to rb[0] and output it back, with the stack at 7
+        let mut symbolic = SymbolicVM::new(&IntcodeVM::new(vec![109, 7, 203, 0, 204, 0, 99, 0]));
+        symbolic.run_to_end().unwrap();
+        assert_eq!(symbolic.outputs(), &[Expr::Input(0)]);
+
+        let mut adjust = SymbolicVM::new(&IntcodeVM::new(vec![9, 3, 99, 0]));
+        adjust.set_symbol(3, "x").unwrap();
+        assert_eq!(
+            adjust.run_to_end(),
+            Err(SymbolicError::SymbolicRelativeBase { pc: 0 })
+        );
+    }
+}
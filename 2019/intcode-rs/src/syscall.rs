@@ -0,0 +1,333 @@
+use crate::device::xorshift;
+use crate::isa::{Control, InstructionSet, Intcode, Role, Signature};
+use crate::{ExecutionError, IntcodeVM, ParameterMode, Result};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The opcode reserved for host calls
+pub const SYSCALL: i64 = 80;
+
+/// Print a zero-terminated string: `print(string) -> length`
+pub const PRINT: i64 = 1;
+/// Get a random number: `random(low, high) -> low..high`, where `low < high`
+pub const RANDOM: i64 = 2;
+/// Read a file into memory: `read_file(path, buffer, size) -> length or -1`
+pub const READ_FILE: i64 = 3;
+
+type Handler = Box<dyn Fn(&mut IntcodeVM) -> Result<i64> + Send + Sync>;
+
+/// An instruction set with a host call instruction, which runs Rust functions
+///
+/// `sys n` (opcode 80, with one parameter) calls the handler registered for
+/// the number `n`. Arguments are passed on the relative-base stack, so the
+/// first is at `rb[0]`, the second at `rb[1]`, and so on. Strings are passed
+/// as the address of a zero-terminated run of characters. The handler's
+/// return value replaces the first argument, at `rb[0]`.
+///
+/// Calling a number with no handler fails with `UnknownSyscall`, and a
+/// standard handler given arguments it can't use fails with
+/// `InvalidSyscallArgument`. Everything
+/// other than the host call instruction is passed on to the base set.
+pub struct Syscalls<I = Intcode> {
+    base: I,
+    handlers: BTreeMap<i64, Handler>,
+}
+
+impl<I: InstructionSet> Syscalls<I> {
+    /// Add host calls to an instruction set, with no handlers registered
+    pub fn new(base: I) -> Self {
+        Self {
+            base,
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Register a handler for a call number, replacing any existing one
+    pub fn register<F>(&mut self, number: i64, handler: F)
+    where
+        F: Fn(&mut IntcodeVM) -> Result<i64> + Send + Sync + 'static,
+    {
+        self.handlers.insert(number, Box::new(handler));
+    }
+
+    /// Register the standard handlers, `PRINT`, `RANDOM` and `READ_FILE`
+    ///
+    /// `PRINT` writes to stdout. `RANDOM` is a simple xorshift generator
+    /// starting from `seed`, so runs can be repeated.
+    pub fn register_standard(&mut self, seed: u64) {
+        self.register(PRINT, |vm| {
+            let string = string(vm, argument(vm, 0)?)?;
+            print!("{}", string);
+            std::io::stdout().flush().ok();
+            Ok(string.chars().count() as i64)
+        });
+
+        // Xorshift gets stuck at zero
+        let state = AtomicU64::new(seed.max(1));
+        self.register(RANDOM, move |vm| {
+            let (low, high) = (argument(vm, 0)?, argument(vm, 1)?);
+
+            if high <= low {
+                return Err(ExecutionError::InvalidSyscallArgument(RANDOM));
+            }
+
+            // Advance in one go, so VMs sharing the handler never get the same number
+            let x = xorshift(
+                state
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| Some(xorshift(s)))
+                    .unwrap(),
+            );
+
+            // The range can be wider than an i64, but never wider than a u64
+            let span = (high as i128 - low as i128) as u64;
+            Ok((low as i128 + (x % span) as i128) as i64)
+        });
+
+        self.register(READ_FILE, |vm| {
+            let path = string(vm, argument(vm, 0)?)?;
+            let buffer = to_address(argument(vm, 1)?)?;
+            let size = argument(vm, 2)?.max(0) as usize;
+
+            let contents = match std::fs::read(path) {
+                Ok(contents) => contents,
+                Err(_) => return Ok(-1),
+            };
+
+            let length = contents.len().min(size);
+            for (i, byte) in contents[..length].iter().enumerate() {
+                vm.set_memory(buffer + i, *byte as i64)?;
+            }
+
+            Ok(length as i64)
+        });
+    }
+}
+
+impl<I: InstructionSet> InstructionSet for Syscalls<I> {
+    fn signature(&self, opcode: i64) -> Option<Signature> {
+        if opcode == SYSCALL {
+            Some(Signature {
+                mnemonic: "sys",
+                roles: &[Role::Read],
+            })
+        } else {
+            self.base.signature(opcode)
+        }
+    }
+
+    fn execute(&self, vm: &mut IntcodeVM, raw: i64) -> Result<Control> {
+        if raw % 100 != SYSCALL {
+            return self.base.execute(vm, raw);
+        }
+
+        let number = vm.get_parameter(ParameterMode::from_opcode(raw, 0)?, 1)?;
+        let handler = self
+            .handlers
+            .get(&number)
+            .ok_or(ExecutionError::UnknownSyscall(number))?;
+
+        let result = handler(vm)?;
+        vm.set_memory(to_address(vm.relative_base())?, result)?;
+
+        Ok(Control::Next)
+    }
+}
+
+/// Get an argument to a host call, counting from 0
+///
+/// Like `string`, this reads plain memory with `IntcodeVM::peek`, so looking
+/// at arguments never disturbs a mapped device.
+pub fn argument(vm: &IntcodeVM, index: usize) -> Result<i64> {
+    let address = vm
+        .relative_base()
+        .checked_add(index as i64)
+        .ok_or(ExecutionError::InvalidAddress)?;
+
+    vm.peek(to_address(address)?)
+}
+
+/// Read a zero-terminated string out of memory
+pub fn string(vm: &IntcodeVM, address: i64) -> Result<String> {
+    let mut string = String::new();
+
+    for address in to_address(address)?.. {
+        match vm.peek(address)? {
+            0 => break,
+            c => string.push(std::char::from_u32(c as u32).unwrap_or('\u{fffd}')),
+        }
+    }
+
+    Ok(string)
+}
+
+fn to_address(value: i64) -> Result<usize> {
+    IntcodeVM::value_to_index(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn print() {
+        let printed = Arc::new(Mutex::new(String::new()));
+        let mut isa = Syscalls::new(Intcode);
+        let sink = printed.clone();
+        isa.register(PRINT, move |vm| {
+            let string = string(vm, argument(vm, 0)?)?;
+            sink.lock().unwrap().push_str(&string);
+            Ok(string.len() as i64)
+        });
+
+        let mut memory = vec![
+            109, 20, // Stack at 20
+            21101, 0, 12, 0, // Argument is the string at 12
+            180, 1, // Print
+            204, 0, // Output the length
+            99, 0, // Padding
+            72, 105, 33, 0, // "Hi!"
+        ];
+        memory.resize(22, 0);
+
+        let mut vm = IntcodeVM::new(memory);
+        vm.run_to_end_with(&isa).unwrap();
+        assert_eq!(*printed.lock().unwrap(), "Hi!");
+        assert_eq!(vm.pop_output(), Some(3));
+
+        let mut vm = IntcodeVM::new(vec![180, 5, 99]);
+        assert_eq!(
+            vm.run_to_end_with(&isa),
+            Err(ExecutionError::UnknownSyscall(5))
+        );
+    }
+
+    #[test]
+    fn random() {
+        let program = vec![
+            109, 20, // Stack at 20
+            21101, 0, 10, 0, // Low
+            21101, 0, 20, 1, // High
+            180, 2, // Random
+            204, 0, // Output it
+            1105, 1, 2, // Again
+            0, 0, 0, 0, 0,
+        ];
+        let run = |seed| {
+            let mut isa = Syscalls::new(Intcode);
+            isa.register_standard(seed);
+
+            let mut vm = IntcodeVM::new(program.clone());
+            for _ in 0..50 {
+                vm.step_with(&isa).unwrap();
+            }
+            vm.iter_output().copied().collect::<Vec<_>>()
+        };
+
+        let first = run(42);
+        assert_eq!(first.len(), 10);
+        assert!(first.iter().all(|n| (10..20).contains(n)));
+        assert!(first.iter().any(|n| *n != first[0]));
+        assert_eq!(run(42), first);
+        assert_ne!(run(43), first);
+
+        // The widest range, then an empty one
+        let mut isa = Syscalls::new(Intcode);
+        isa.register_standard(42);
+        let mut vm = IntcodeVM::new(program.clone());
+        vm.set_memory(4, i64::MIN).unwrap();
+        vm.set_memory(8, i64::MAX).unwrap();
+        for _ in 0..50 {
+            vm.step_with(&isa).unwrap();
+        }
+        assert!(vm.iter_output().any(|n| *n < 0));
+        assert!(vm.iter_output().any(|n| *n > 0));
+
+        let mut isa = Syscalls::new(Intcode);
+        isa.register_standard(42);
+        let mut vm = IntcodeVM::new(program.clone());
+        vm.set_memory(8, 10).unwrap();
+        assert_eq!(
+            vm.run_to_end_with(&isa),
+            Err(ExecutionError::InvalidSyscallArgument(RANDOM))
+        );
+
+        // The rejected call didn't use up a number
+        let mut vm = IntcodeVM::new(program.clone());
+        for _ in 0..50 {
+            vm.step_with(&isa).unwrap();
+        }
+        assert_eq!(vm.iter_output().copied().collect::<Vec<_>>(), first);
+
+        // VMs on different threads sharing the generator each get their own numbers
+        let mut numbers: Vec<i64> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let (isa, mut vm) = (&isa, IntcodeVM::new(program.clone()));
+                    vm.set_memory(4, i64::MIN).unwrap();
+                    vm.set_memory(8, i64::MAX).unwrap();
+
+                    scope.spawn(move || {
+                        for _ in 0..1000 {
+                            vm.step_with(isa).unwrap();
+                        }
+                        vm.iter_output().copied().collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect()
+        });
+        numbers.sort_unstable();
+        numbers.dedup();
+        assert_eq!(numbers.len(), 800);
+    }
+
+    #[test]
+    fn arguments_out_of_range() {
+        let mut vm = IntcodeVM::new(vec![109, i64::MAX, 99]);
+        vm.run_to_end().unwrap();
+
+        assert!(argument(&vm, 0).is_err());
+        assert_eq!(argument(&vm, 1), Err(ExecutionError::InvalidAddress));
+    }
+
+    #[test]
+    fn read_file() {
+        let path = std::env::temp_dir().join(format!("intcode-syscall-{}", std::process::id()));
+        std::fs::write(&path, "abc").unwrap();
+
+        let mut memory = vec![
+            109, 0, // Stack, set below
+            21101, 0, 0, 0, // Path, set below
+            21101, 0, 0, 1, // Buffer, set below
+            21101, 0, 2, 2, // Size
+            180, 3, // Read the file
+            204, 0, // Output the length
+            99,
+        ];
+        let path_address = memory.len();
+        memory.extend(path.to_str().unwrap().chars().map(|c| c as i64));
+        memory.push(0);
+        let buffer = memory.len();
+        let stack = buffer + 3;
+        memory.resize(stack + 3, 0);
+        memory[1] = stack as i64;
+        memory[4] = path_address as i64;
+        memory[8] = buffer as i64;
+
+        let mut isa = Syscalls::new(Intcode);
+        isa.register_standard(1);
+
+        let mut vm = IntcodeVM::new(memory);
+        vm.run_to_end_with(&isa).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(vm.pop_output(), Some(2));
+        assert_eq!(&vm.memory()[buffer..buffer + 3], &[97, 98, 0]);
+    }
+}
@@ -0,0 +1,360 @@
+use crate::{ExecutionError, IntcodeVM, Opcode, ParameterMode, Result};
+use std::collections::{BTreeSet, VecDeque};
+
+/// The set of labels a value depends on
+pub type Taint = BTreeSet<String>;
+
+/// A conditional jump that was executed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Branch {
+    /// The address of the jump instruction
+    pub pc: usize,
+    /// Whether the jump was taken
+    pub taken: bool,
+    /// The labels the condition depended on
+    pub condition: Taint,
+    /// The labels the jump target depended on
+    pub target: Taint,
+}
+
+/// A VM that tracks which labelled values flow into each memory cell and output
+///
+/// Every input and any chosen memory cells can be given labels. Labels flow
+/// through arithmetic, comparisons, and memory writes: the result of an
+/// instruction carries the labels of all of its operands, including any
+/// pointers used to find them. Every output is recorded with its labels, as
+/// is the condition and target of every conditional jump.
+///
+/// Only data flow is tracked. A value written on one side of a branch does
+/// not pick up the labels of the branch condition. Values read from devices
+/// mapped past the end of memory are unlabelled, and writes to them are
+/// dropped.
+#[derive(Debug, Clone)]
+pub struct TaintTracker {
+    vm: IntcodeVM,
+    memory: Vec<Taint>,
+    input: VecDeque<Taint>,
+    outputs: Vec<(i64, Taint)>,
+    branches: Vec<Branch>,
+}
+
+impl TaintTracker {
+    /// Start tracking a VM
+    ///
+    /// Anything already in the VM's input queue is treated as unlabelled.
+    pub fn new(vm: IntcodeVM) -> Self {
+        Self {
+            memory: vec![Taint::new(); vm.memory().len()],
+            input: vm.input.iter().map(|_| Taint::new()).collect(),
+            outputs: Vec::new(),
+            branches: Vec::new(),
+            vm,
+        }
+    }
+
+    /// Get the VM being tracked
+    pub fn vm(&self) -> &IntcodeVM {
+        &self.vm
+    }
+
+    /// Add a label to a memory cell
+    pub fn label_memory(&mut self, index: usize, label: &str) -> Result<()> {
+        self.memory
+            .get_mut(index)
+            .ok_or(ExecutionError::InvalidAddress)?
+            .insert(label.to_string());
+
+        Ok(())
+    }
+
+    /// Add a labelled value to the end of the input queue
+    pub fn push_input(&mut self, input: i64, label: &str) {
+        let mut taint = Taint::new();
+        taint.insert(label.to_string());
+
+        self.vm.push_input(input);
+        self.input.push_back(taint);
+    }
+
+    /// Get the labels on a memory cell
+    pub fn memory_taint(&self, index: usize) -> Option<&Taint> {
+        self.memory.get(index)
+    }
+
+    /// Get every output so far, along with its labels
+    pub fn outputs(&self) -> &[(i64, Taint)] {
+        &self.outputs
+    }
+
+    /// Get every conditional jump executed so far
+    pub fn branches(&self) -> &[Branch] {
+        &self.branches
+    }
+
+    /// Get the outputs that depended on a label
+    pub fn outputs_depending_on<'a>(&'a self, label: &'a str) -> impl Iterator<Item = i64> + 'a {
+        self.outputs
+            .iter()
+            .filter(move |(_, taint)| taint.contains(label))
+            .map(|(value, _)| *value)
+    }
+
+    /// Get the conditional jumps whose condition depended on a label
+    pub fn branches_depending_on<'a>(
+        &'a self,
+        label: &'a str,
+    ) -> impl Iterator<Item = &'a Branch> + 'a {
+        self.branches
+            .iter()
+            .filter(move |branch| branch.condition.contains(label))
+    }
+
+    /// Take a single step through the program, tracking labels along the way
+    ///
+    /// Behaves exactly like `IntcodeVM::step`.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.vm.halted() {
+            return self.vm.step();
+        }
+
+        let pc = self.vm.pc();
+        let opcode = Opcode::from_raw(self.vm.current_raw_opcode()?)?;
+
+        // Work out where everything flows before the VM changes memory
+        let effect = match opcode {
+            Opcode::Add(in1, in2, out)
+            | Opcode::Multiply(in1, in2, out)
+            | Opcode::LessThan(in1, in2, out)
+            | Opcode::Equals(in1, in2, out) => {
+                let mut taint = self.parameter(in1, 1)?;
+                taint.extend(self.parameter(in2, 2)?);
+                Some(self.destination(out, 3, taint)?)
+            }
+            Opcode::Input(out) => {
+                let taint = self.input.front().cloned().unwrap_or_default();
+                Some(self.destination(out, 1, taint)?)
+            }
+            Opcode::Output(in1) => {
+                let taint = self.parameter(in1, 1)?;
+                self.outputs.push((0, taint));
+                None
+            }
+            Opcode::JumpIfTrue(in1, in2) | Opcode::JumpIfFalse(in1, in2) => {
+                let condition = self.parameter(in1, 1)?;
+                let target = self.parameter(in2, 2)?;
+                self.branches.push(Branch {
+                    pc,
+                    taken: false,
+                    condition,
+                    target,
+                });
+                None
+            }
+            Opcode::AdjustRelativeBase(..) | Opcode::Halt => None,
+        };
+
+        let result = self.vm.step();
+
+        match (&result, &opcode) {
+            (Ok(_), Opcode::Input(..)) => {
+                self.input.pop_front();
+            }
+            (Ok(_), Opcode::Output(..)) => {
+                let value = *self.vm.iter_output().last().unwrap();
+                self.outputs.last_mut().unwrap().0 = value;
+            }
+            (Ok(_), Opcode::JumpIfTrue(..)) | (Ok(_), Opcode::JumpIfFalse(..)) => {
+                self.branches.last_mut().unwrap().taken = self.vm.pc() != pc + opcode.size();
+            }
+            (Err(_), Opcode::Output(..)) => {
+                self.outputs.pop();
+            }
+            (Err(_), Opcode::JumpIfTrue(..)) | (Err(_), Opcode::JumpIfFalse(..)) => {
+                self.branches.pop();
+            }
+            _ => {}
+        }
+
+        // Devices can be mapped past the end of memory, and aren't tracked
+        if let (Ok(_), Some((index, taint))) = (&result, effect) {
+            if let Some(cell) = self.memory.get_mut(index) {
+                *cell = taint;
+            }
+        }
+
+        result
+    }
+
+    /// Run the program until it halts
+    pub fn run_to_end(&mut self) -> Result<()> {
+        while self.step()? {}
+
+        Ok(())
+    }
+
+    fn cell(&self, index: usize) -> Taint {
+        self.memory.get(index).cloned().unwrap_or_default()
+    }
+
+    fn parameter(&self, mode: ParameterMode, offset: usize) -> Result<Taint> {
+        let index = self.vm.pc() + offset;
+        let mut taint = self.cell(index);
+
+        let pointer = match mode {
+            ParameterMode::Immediate => return Ok(taint),
+            ParameterMode::Position => Some(self.vm.peek(index)?),
+            ParameterMode::Relative => self.vm.relative_base().checked_add(self.vm.peek(index)?),
+        };
+
+        // A pointer out of range makes the VM fail, so its labels don't matter
+        if let Some(pointer) = pointer.filter(|pointer| *pointer >= 0) {
+            taint.extend(self.cell(pointer as usize));
+        }
+
+        Ok(taint)
+    }
+
+    /// Find the cell a write will go to, and add the pointer's labels to the value
+    fn destination(
+        &self,
+        mode: ParameterMode,
+        offset: usize,
+        mut taint: Taint,
+    ) -> Result<(usize, Taint)> {
+        let index = self.vm.pc() + offset;
+        let pointer = match mode {
+            ParameterMode::Relative => self.vm.relative_base().checked_add(self.vm.peek(index)?),
+            _ => Some(self.vm.peek(index)?),
+        };
+
+        let pointer = match pointer {
+            Some(pointer) if mode != ParameterMode::Immediate && pointer >= 0 => pointer,
+            // The VM is about to fail, so the destination doesn't matter
+            _ => return Ok((index, taint)),
+        };
+
+        taint.extend(self.cell(index));
+        Ok((pointer as usize, taint))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn labels(names: &[&str]) -> Taint {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn inputs_to_outputs() {
+        // Take two inputs, sum them, and quadruple them, then output the
+        // quadrupled sum and the second input
+        let mut tracker = TaintTracker::new(IntcodeVM::new(vec![
+            3, 0, 3, 1, 1, 0, 1, 0, 102, 4, 0, 0, 4, 0, 4, 1, 104, 5, 99,
+        ]));
+        tracker.push_input(4, "a");
+        tracker.push_input(6, "b");
+        tracker.run_to_end().unwrap();
+
+        assert_eq!(
+            tracker.outputs(),
+            &[
+                (40, labels(&["a", "b"])),
+                (6, labels(&["b"])),
+                (5, labels(&[]))
+            ]
+        );
+        assert_eq!(
+            tracker.outputs_depending_on("a").collect::<Vec<_>>(),
+            vec![40]
+        );
+        assert_eq!(tracker.memory_taint(0), Some(&labels(&["a", "b"])));
+    }
+
+    #[test]
+    fn branches() {
+        // Output 1000 if the input equals the labelled cell, otherwise 999
+        let mut tracker = TaintTracker::new(IntcodeVM::new(vec![
+            3, 15, 8, 15, 16, 17, 1005, 17, 12, 104, 999, 99, 104, 1000, 99, 0, 8, 0,
+        ]));
+        tracker.label_memory(16, "secret").unwrap();
+        tracker.push_input(8, "guess");
+        tracker.run_to_end().unwrap();
+
+        assert_eq!(
+            tracker.branches(),
+            &[Branch {
+                pc: 6,
+                taken: true,
+                condition: labels(&["guess", "secret"]),
+                target: labels(&[]),
+            }]
+        );
+        assert_eq!(tracker.branches_depending_on("secret").count(), 1);
+        assert_eq!(tracker.outputs(), &[(1000, labels(&[]))]);
+    }
+
+    #[test]
+    fn matches_vm() {
+        let program = vec![3, 0, 4, 0, 3, 0, 4, 0, 99];
+
+        let mut tracker = TaintTracker::new(IntcodeVM::new(program.clone()));
+        tracker.push_input(3, "x");
+        assert_eq!(tracker.run_to_end(), Err(ExecutionError::NeedsInput));
+        assert_eq!(tracker.outputs(), &[(3, labels(&["x"]))]);
+
+        tracker.push_input(7, "y");
+        assert_eq!(tracker.run_to_end(), Ok(()));
+        assert_eq!(tracker.outputs()[1], (7, labels(&["y"])));
+        assert_eq!(tracker.step(), Err(ExecutionError::AlreadyHalted));
+    }
+
+    #[test]
+    fn relative_base() {
+        // Input to rb[0], add 5 into rb[1] and output it, with the stack at 11
+        let mut tracker = TaintTracker::new(IntcodeVM::new(vec![
+            109, 11, 203, 0, 21201, 0, 5, 1, 204, 1, 99, 0, 0,
+        ]));
+        tracker.push_input(2, "a");
+        tracker.run_to_end().unwrap();
+
+        assert_eq!(tracker.outputs(), &[(7, labels(&["a"]))]);
+        assert_eq!(tracker.memory_taint(12), Some(&labels(&["a"])));
+    }
+
+    #[test]
+    fn relative_overflow() {
+        let mut tracker = TaintTracker::new(IntcodeVM::new(vec![109, i64::MAX, 203, 1, 99]));
+        tracker.push_input(1, "a");
+        assert_eq!(tracker.run_to_end(), Err(ExecutionError::InvalidAddress));
+
+        let mut tracker = TaintTracker::new(IntcodeVM::new(vec![109, i64::MAX, 204, 1, 99]));
+        assert_eq!(tracker.run_to_end(), Err(ExecutionError::InvalidAddress));
+    }
+
+    #[test]
+    fn device_past_memory() {
+        let mut vm = IntcodeVM::new(vec![1101, 1, 2, 10, 4, 10, 99]);
+        vm.map_device(10..11, crate::device::Framebuffer::new(1, 1));
+
+        let mut tracker = TaintTracker::new(vm);
+        tracker.run_to_end().unwrap();
+
+        assert_eq!(tracker.outputs(), &[(3, labels(&[]))]);
+        assert_eq!(tracker.memory_taint(10), None);
+    }
+
+    #[test]
+    fn queued_before_tracking() {
+        let mut vm = IntcodeVM::new(vec![3, 7, 3, 8, 99, 0, 0, 0, 0]);
+        vm.push_input(5);
+
+        let mut tracker = TaintTracker::new(vm);
+        tracker.push_input(6, "secret");
+        tracker.run_to_end().unwrap();
+
+        assert_eq!(tracker.memory_taint(7), Some(&labels(&[])));
+        assert_eq!(tracker.memory_taint(8), Some(&labels(&["secret"])));
+    }
+}
@@ -0,0 +1,434 @@
+use crate::{ExecutionError, IntcodeVM, Result};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long a blocked VM or the supervisor waits before checking in again
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The final state of a VM that was run on its own thread
+#[derive(Debug)]
+pub struct Finished {
+    /// The VM as it was when its thread stopped
+    pub vm: IntcodeVM,
+    /// `Ok` if the VM halted, or the error that stopped it
+    pub result: Result<()>,
+    /// The VM's input channel, including anything it never got to read
+    pub input: Receiver<i64>,
+}
+
+/// Everything that happened to a set of threaded VMs
+#[derive(Debug)]
+pub struct Report {
+    /// The final state of each VM, in the order they were spawned
+    pub finished: Vec<Finished>,
+    /// True if the VMs were stopped because none of them could make progress
+    pub deadlocked: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// For each VM, the epoch at which it last found its input empty
+    blocked: Vec<Option<u64>>,
+    finished: Vec<bool>,
+    /// Incremented every time any VM sends or receives a value
+    ///
+    /// Values only go into or out of the channels while the state is locked,
+    /// so the count always matches what the channels hold.
+    activity: u64,
+    epoch: u64,
+    stop: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Runs VMs on their own threads, connected by `mpsc` channels
+///
+/// Unlike the single-threaded schedulers, the interleaving of VMs is up to the
+/// OS. Programs that only communicate through their channels get the same
+/// results either way, but anything that depends on timing (such as the
+/// `Network` returning `-1` on empty input) does not.
+///
+/// When every running VM is waiting on an empty channel and nothing is sent
+/// or received, the supervisor reports a deadlock and stops them. Values
+/// sent into the channels from outside the supervisor race with this check,
+/// so they should be sent before calling `join`.
+#[derive(Debug, Default)]
+pub struct Supervisor {
+    shared: Arc<Shared>,
+    handles: Vec<JoinHandle<Finished>>,
+}
+
+impl Supervisor {
+    /// Create a supervisor with no VMs
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start running a VM on a new thread
+    ///
+    /// Inputs are read from `input` once the VM's own input queue is empty,
+    /// and every output is sent to `output`. Outputs sent after the receiver
+    /// has hung up are dropped. Returns the index of the VM in the report.
+    pub fn spawn(&mut self, vm: IntcodeVM, input: Receiver<i64>, output: Sender<i64>) -> usize {
+        let id = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.blocked.push(None);
+            state.finished.push(false);
+            state.blocked.len() - 1
+        };
+
+        let shared = self.shared.clone();
+
+        self.handles.push(std::thread::spawn(move || {
+            // Marks the VM finished even if it panics, so `join` can pass it on
+            let _finishing = Finishing {
+                id,
+                shared: &shared,
+            };
+
+            run_thread(id, &shared, vm, input, output)
+        }));
+
+        id
+    }
+
+    /// Wait for every VM to halt, fail, or deadlock
+    ///
+    /// If a VM's thread panicked, for example in a device or observer, the
+    /// panic is passed on once every VM has stopped.
+    pub fn join(self) -> Report {
+        let count = self.handles.len();
+        let mut deadlocked = false;
+        let mut candidate: Option<(u64, u64)> = None;
+
+        let mut state = self.shared.state.lock().unwrap();
+
+        while !state.finished.iter().all(|f| *f) {
+            let all_blocked = (0..count).all(|i| state.finished[i] || state.blocked[i].is_some());
+
+            if !all_blocked || state.stop {
+                candidate = None;
+            } else if let Some((activity, epoch)) = candidate {
+                if state.activity != activity {
+                    candidate = None;
+                } else if (0..count)
+                    .filter(|i| !state.finished[*i])
+                    .all(|i| state.blocked[i] > Some(epoch))
+                {
+                    // Every VM has looked at its input again since everyone
+                    // was first seen blocked, and nothing was sent or received
+                    deadlocked = true;
+                    state.stop = true;
+                }
+            } else {
+                candidate = Some((state.activity, state.epoch));
+                state.epoch += 1;
+                self.shared.changed.notify_all();
+            }
+
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+
+        drop(state);
+
+        let finished = self
+            .handles
+            .into_iter()
+            .map(|handle| match handle.join() {
+                Ok(finished) => finished,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+            .collect();
+
+        Report {
+            finished,
+            deadlocked,
+        }
+    }
+}
+
+/// Marks a VM as finished when its thread stops, however it stops
+struct Finishing<'a> {
+    id: usize,
+    shared: &'a Shared,
+}
+
+impl Drop for Finishing<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .shared
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.finished[self.id] = true;
+        state.blocked[self.id] = None;
+        self.shared.changed.notify_all();
+    }
+}
+
+fn run_thread(
+    id: usize,
+    shared: &Shared,
+    mut vm: IntcodeVM,
+    input: Receiver<i64>,
+    output: Sender<i64>,
+) -> Finished {
+    let result = loop {
+        let stepped = vm.step();
+
+        while let Some(value) = vm.pop_output() {
+            let mut state = shared.state.lock().unwrap();
+            let _ = output.send(value);
+            state.activity += 1;
+            shared.changed.notify_all();
+        }
+
+        match stepped {
+            Ok(true) => {}
+            Ok(false) => break Ok(()),
+            Err(ExecutionError::NeedsInput) => match receive(id, shared, &input) {
+                Some(value) => vm.push_input(value),
+                None => break Err(ExecutionError::NeedsInput),
+            },
+            Err(e) => break Err(e),
+        }
+    };
+
+    Finished { vm, result, input }
+}
+
+/// Wait for a value on the input channel
+///
+/// The channel is only read with the state locked, so taking a value, clearing
+/// the blocked flag and counting the activity all happen at once. Returns
+/// `None` if the channel hung up or the supervisor stopped the VM.
+fn receive(id: usize, shared: &Shared, input: &Receiver<i64>) -> Option<i64> {
+    let mut state = shared.state.lock().unwrap();
+
+    loop {
+        match input.try_recv() {
+            Ok(value) => {
+                state.blocked[id] = None;
+                state.activity += 1;
+                shared.changed.notify_all();
+                return Some(value);
+            }
+            Err(TryRecvError::Disconnected) => return None,
+            Err(TryRecvError::Empty) => {}
+        }
+
+        if state.stop {
+            return None;
+        }
+
+        if state.blocked[id] != Some(state.epoch) {
+            state.blocked[id] = Some(state.epoch);
+            shared.changed.notify_all();
+        }
+
+        state = shared.changed.wait_timeout(state, POLL_INTERVAL).unwrap().0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    const FEEDBACK_AMPLIFIER: &[i64] = &[
+        3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+        1005, 28, 6, 99, 0, 0, 5,
+    ];
+
+    fn run_threaded(settings: &[i64]) -> Report {
+        let mut supervisor = Supervisor::new();
+
+        let channels: Vec<_> = settings
+            .iter()
+            .map(|setting| {
+                let (tx, rx) = channel();
+                tx.send(*setting).unwrap();
+                (tx, rx)
+            })
+            .collect();
+
+        let senders: Vec<_> = channels.iter().map(|(tx, _)| tx.clone()).collect();
+        senders[0].send(0).unwrap();
+
+        for (i, (_, rx)) in channels.into_iter().enumerate() {
+            let tx = senders[(i + 1) % senders.len()].clone();
+            supervisor.spawn(IntcodeVM::new(FEEDBACK_AMPLIFIER), rx, tx);
+        }
+
+        drop(senders);
+
+        supervisor.join()
+    }
+
+    fn run_sequential(settings: &[i64]) -> i64 {
+        let mut amps: Vec<_> = settings
+            .iter()
+            .map(|setting| {
+                let mut vm = IntcodeVM::new(FEEDBACK_AMPLIFIER);
+                vm.push_input(*setting);
+                vm
+            })
+            .collect();
+
+        let mut signal = 0;
+
+        loop {
+            for amp in amps.iter_mut() {
+                amp.push_input(signal);
+
+                match amp.next_output().unwrap() {
+                    Some(output) => signal = output,
+                    None => return signal,
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn feedback_loop() {
+        let report = run_threaded(&[9, 8, 7, 6, 5]);
+
+        assert!(!report.deadlocked);
+        assert_eq!(report.finished.len(), 5);
+
+        for finished in report.finished.iter() {
+            assert_eq!(finished.result, Ok(()));
+            assert!(finished.vm.halted());
+        }
+
+        // The last amplifier's final output is left unread by the first
+        assert_eq!(report.finished[0].input.try_recv(), Ok(139629729));
+    }
+
+    #[test]
+    fn matches_sequential() {
+        for settings in &[[9, 8, 7, 6, 5], [9, 7, 8, 5, 6], [5, 6, 7, 8, 9]] {
+            let report = run_threaded(settings);
+
+            assert_eq!(
+                report.finished[0].input.try_recv(),
+                Ok(run_sequential(settings))
+            );
+        }
+    }
+
+    #[test]
+    fn stress() {
+        let expected = run_sequential(&[9, 7, 8, 5, 6]);
+
+        for _ in 0..50 {
+            let report = run_threaded(&[9, 7, 8, 5, 6]);
+            assert!(!report.deadlocked);
+            assert_eq!(report.finished[0].input.try_recv(), Ok(expected));
+        }
+
+        // Pass a counter round a ring, each VM adding one, until it reaches
+        // 2000, so values are constantly in flight while everyone else waits
+        const RING: &[i64] = &[
+            3, 20, // Take the counter
+            1001, 20, 1, 20, // Add one
+            4, 20, // Pass it on
+            1007, 20, 2000, 21, // Keep going while it's small
+            1005, 21, 0, // Round again
+            99, 0, 0, 0, 0, 0, 0,
+        ];
+
+        for _ in 0..5 {
+            let mut supervisor = Supervisor::new();
+            let channels: Vec<_> = (0..8).map(|_| channel()).collect();
+            let senders: Vec<_> = channels.iter().map(|(tx, _)| tx.clone()).collect();
+            senders[0].send(0).unwrap();
+
+            for (i, (_, rx)) in channels.into_iter().enumerate() {
+                supervisor.spawn(IntcodeVM::new(RING), rx, senders[(i + 1) % 8].clone());
+            }
+            drop(senders);
+
+            let report = supervisor.join();
+            assert!(!report.deadlocked);
+            assert!(report.finished.iter().any(|f| f.vm.halted()));
+        }
+    }
+
+    #[test]
+    fn deadlock() {
+        let mut supervisor = Supervisor::new();
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+
+        // Each VM waits for the other to say something first
+        supervisor.spawn(IntcodeVM::new(vec![3, 0, 4, 0, 99]), a_rx, b_tx);
+        supervisor.spawn(IntcodeVM::new(vec![3, 0, 4, 0, 99]), b_rx, a_tx);
+
+        let report = supervisor.join();
+
+        assert!(report.deadlocked);
+
+        for finished in report.finished.iter() {
+            assert_eq!(finished.result, Err(ExecutionError::NeedsInput));
+            assert!(!finished.vm.halted());
+        }
+    }
+
+    #[test]
+    fn panic() {
+        struct Broken;
+
+        impl crate::device::Device for Broken {
+            fn read(&mut self, _offset: usize) -> i64 {
+                panic!("broken device")
+            }
+
+            fn write(&mut self, _offset: usize, _value: i64) {}
+        }
+
+        let mut supervisor = Supervisor::new();
+        let (tx, rx) = channel();
+        let (out_tx, _out_rx) = channel();
+
+        let mut vm = IntcodeVM::new(vec![4, 10, 99]);
+        vm.map_device(10..11, Broken);
+        supervisor.spawn(vm, channel().1, tx);
+        supervisor.spawn(IntcodeVM::new(vec![3, 0, 99]), rx, out_tx);
+
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| supervisor.join()))
+            .unwrap_err();
+        assert_eq!(panic.downcast_ref::<&str>(), Some(&"broken device"));
+    }
+
+    #[test]
+    fn error_and_hang_up() {
+        let mut supervisor = Supervisor::new();
+        let (tx, rx) = channel();
+        let (out_tx, out_rx) = channel();
+
+        supervisor.spawn(IntcodeVM::new(vec![104, 7, 42]), channel().1, tx);
+        supervisor.spawn(IntcodeVM::new(vec![3, 0, 4, 0, 3, 0, 99]), rx, out_tx);
+
+        let report = supervisor.join();
+
+        assert!(!report.deadlocked);
+        assert_eq!(
+            report.finished[0].result,
+            Err(ExecutionError::UnknownOpcode(42))
+        );
+        assert_eq!(report.finished[1].result, Err(ExecutionError::NeedsInput));
+        assert_eq!(out_rx.try_iter().collect::<Vec<_>>(), vec![7]);
+    }
+}
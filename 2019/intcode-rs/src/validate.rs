@@ -0,0 +1,173 @@
+use crate::cfg::{Cfg, Exit};
+use crate::disasm::Instruction;
+use crate::{ExecutionError, ParameterMode};
+use std::fmt;
+
+/// A problem found in a program image before running it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A reachable address holds a value that isn't a known opcode
+    UnknownOpcode(i64),
+    /// A reachable instruction has a parameter mode that isn't known
+    UnknownMode(u8),
+    /// An instruction writes to a parameter in immediate mode, which always fails
+    ImmediateWrite { parameter: usize },
+    /// An opcode has mode digits past the end of its parameters
+    UnusedModes(i64),
+    /// A jump has an immediate target outside of memory
+    JumpOutOfBounds(i64),
+    /// A reachable instruction runs past the end of memory
+    Truncated,
+    /// Execution can go on past the last instruction in memory without halting
+    RunsOffEnd,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            Problem::UnknownMode(mode) => write!(f, "unknown parameter mode {}", mode),
+            Problem::ImmediateWrite { parameter } => {
+                write!(
+                    f,
+                    "parameter {} is written in immediate mode",
+                    parameter + 1
+                )
+            }
+            Problem::UnusedModes(raw) => write!(f, "opcode {} has unused mode digits", raw),
+            Problem::JumpOutOfBounds(target) => {
+                write!(f, "jump to {}, outside of memory", target)
+            }
+            Problem::Truncated => write!(f, "instruction runs past the end of memory"),
+            Problem::RunsOffEnd => write!(f, "execution runs off the end of memory"),
+        }
+    }
+}
+
+/// Check a program image for problems that would make it fail at runtime
+///
+/// Only code reachable from address 0 is checked, as found by `cfg::Cfg`, so
+/// data that happens to look like bad instructions is ignored. Problems are
+/// returned with their addresses, in address order.
+pub fn validate(memory: &[i64]) -> Vec<(usize, Problem)> {
+    let cfg = Cfg::new(memory);
+    let mut problems = Vec::new();
+
+    for (address, error) in cfg.invalid().iter() {
+        let problem = match error {
+            ExecutionError::UnknownOpcode(_) => Problem::UnknownOpcode(memory[*address]),
+            ExecutionError::UnknownMode(mode) => Problem::UnknownMode(*mode),
+            // Running off the end is reported at the instruction that gets there
+            ExecutionError::InvalidPC if *address < memory.len() => Problem::Truncated,
+            _ => continue,
+        };
+
+        problems.push((*address, problem));
+    }
+
+    for block in cfg.blocks().values() {
+        for instruction in block.instructions.iter() {
+            problems.extend(
+                instruction_problems(memory, instruction)
+                    .into_iter()
+                    .map(|problem| (instruction.address, problem)),
+            );
+        }
+
+        let last = block.instructions.last().unwrap();
+
+        for exit in block.exits.iter() {
+            let problem = match exit {
+                Exit::Invalid(target) => Problem::JumpOutOfBounds(*target),
+                Exit::Jump(target) | Exit::Call(target) if *target >= memory.len() => {
+                    Problem::JumpOutOfBounds(*target as i64)
+                }
+                Exit::Next(next) if *next >= memory.len() => Problem::RunsOffEnd,
+                _ => continue,
+            };
+
+            problems.push((last.address, problem));
+        }
+    }
+
+    problems.sort_by_key(|(address, _)| *address);
+    problems
+}
+
+fn instruction_problems(memory: &[i64], instruction: &Instruction) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let modes = instruction.opcode.modes();
+    let raw = memory[instruction.address];
+
+    if let Some(parameter) = instruction.write_parameter() {
+        if modes[parameter] == ParameterMode::Immediate {
+            problems.push(Problem::ImmediateWrite { parameter });
+        }
+    }
+
+    if raw / 10i64.pow(2 + modes.len() as u32) != 0 {
+        problems.push(Problem::UnusedModes(raw));
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn valid_program() {
+        assert_eq!(validate(&[3, 9, 1008, 9, 8, 9, 4, 9, 99, -1]), vec![]);
+    }
+
+    #[test]
+    fn every_problem() {
+        let memory = vec![
+            11101, 1, 2, 3, // Immediate write
+            1104, 5, // Unused mode on output
+            1006, 12, 13, // Maybe jump to the unknown opcode
+            1105, 1, -4, // Jump to a negative address
+            0,  // Condition
+            33, // Unknown opcode
+        ];
+        assert_eq!(
+            validate(&memory),
+            vec![
+                (0, Problem::ImmediateWrite { parameter: 2 }),
+                (4, Problem::UnusedModes(1104)),
+                (9, Problem::JumpOutOfBounds(-4)),
+                (13, Problem::UnknownOpcode(33)),
+            ]
+        );
+
+        assert_eq!(
+            validate(&[1105, 1, 50, 1]),
+            vec![(0, Problem::JumpOutOfBounds(50))]
+        );
+        assert_eq!(validate(&[104, 1, 1, 0]), vec![(2, Problem::Truncated)]);
+        assert_eq!(validate(&[1101, 1, 1, 0]), vec![(0, Problem::RunsOffEnd)]);
+        // Only the branch that doesn't halt runs off the end
+        assert_eq!(
+            validate(&[1006, 7, 6, 104, 1, 99, 104, 0]),
+            vec![(6, Problem::RunsOffEnd)]
+        );
+        assert_eq!(validate(&[304, 0, 99]), vec![(0, Problem::UnknownMode(3))]);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            Problem::ImmediateWrite { parameter: 2 }.to_string(),
+            "parameter 3 is written in immediate mode"
+        );
+        assert_eq!(
+            Problem::JumpOutOfBounds(-4).to_string(),
+            "jump to -4, outside of memory"
+        );
+        assert_eq!(
+            Problem::RunsOffEnd.to_string(),
+            "execution runs off the end of memory"
+        );
+    }
+}
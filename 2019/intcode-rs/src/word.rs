@@ -0,0 +1,181 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A type that can be stored in the memory cells of a `Machine`
+///
+/// Implemented for `i32`, `i64` and `i128`, and for `num_bigint::BigInt` with
+/// the `bigint` feature. Other integer types, such as a different bignum, can
+/// implement it too.
+pub trait Word:
+    Clone + fmt::Debug + PartialEq + PartialOrd + From<i32> + FromStr + Send + Sync
+{
+    /// Convert to an `i64`, or `None` if the value doesn't fit
+    fn to_i64(&self) -> Option<i64>;
+
+    /// Get the value of an instruction as an `i64`
+    ///
+    /// Values that don't fit may be cut down to their lower decimal digits,
+    /// as long as the opcode and the modes of the first 16 parameters stay
+    /// the same.
+    fn instruction(&self) -> i64;
+
+    /// Add, or return `None` on overflow
+    fn checked_add(self, other: Self) -> Option<Self>;
+    /// Add, wrapping around on overflow
+    fn wrapping_add(self, other: Self) -> Self;
+    /// Add, stopping at the largest or smallest value on overflow
+    fn saturating_add(self, other: Self) -> Self;
+
+    /// Multiply, or return `None` on overflow
+    fn checked_mul(self, other: Self) -> Option<Self>;
+    /// Multiply, wrapping around on overflow
+    fn wrapping_mul(self, other: Self) -> Self;
+    /// Multiply, stopping at the largest or smallest value on overflow
+    fn saturating_mul(self, other: Self) -> Self;
+}
+
+/// Enough decimal digits for an opcode and 16 parameter modes
+const INSTRUCTION_DIGITS: i64 = 1_000_000_000_000_000_000;
+
+macro_rules! primitive_word {
+    ($t:ty, $instruction:expr) => {
+        impl Word for $t {
+            fn to_i64(&self) -> Option<i64> {
+                use std::convert::TryInto;
+
+                (*self).try_into().ok()
+            }
+
+            fn instruction(&self) -> i64 {
+                $instruction(*self)
+            }
+
+            fn checked_add(self, other: Self) -> Option<Self> {
+                <$t>::checked_add(self, other)
+            }
+
+            fn wrapping_add(self, other: Self) -> Self {
+                <$t>::wrapping_add(self, other)
+            }
+
+            fn saturating_add(self, other: Self) -> Self {
+                <$t>::saturating_add(self, other)
+            }
+
+            fn checked_mul(self, other: Self) -> Option<Self> {
+                <$t>::checked_mul(self, other)
+            }
+
+            fn wrapping_mul(self, other: Self) -> Self {
+                <$t>::wrapping_mul(self, other)
+            }
+
+            fn saturating_mul(self, other: Self) -> Self {
+                <$t>::saturating_mul(self, other)
+            }
+        }
+    };
+}
+
+primitive_word!(i32, i64::from);
+primitive_word!(i64, |value| value);
+primitive_word!(i128, |value| (value % INSTRUCTION_DIGITS as i128) as i64);
+
+/// Arbitrary-precision cells, which never overflow
+#[cfg(feature = "bigint")]
+impl Word for num_bigint::BigInt {
+    fn to_i64(&self) -> Option<i64> {
+        use std::convert::TryInto;
+
+        self.try_into().ok()
+    }
+
+    fn instruction(&self) -> i64 {
+        (self % INSTRUCTION_DIGITS).to_i64().unwrap()
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Some(self + other)
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        self + other
+    }
+
+    fn saturating_add(self, other: Self) -> Self {
+        self + other
+    }
+
+    fn checked_mul(self, other: Self) -> Option<Self> {
+        Some(self * other)
+    }
+
+    fn wrapping_mul(self, other: Self) -> Self {
+        self * other
+    }
+
+    fn saturating_mul(self, other: Self) -> Self {
+        self * other
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ExecutionError, Machine};
+
+    // Outputs the square of 34915192, from day 9
+    const SQUARE: &str = "1102,34915192,34915192,7,4,7,99,0";
+
+    #[test]
+    fn word_sizes() {
+        let mut vm = Machine::<i32>::parse(SQUARE).unwrap();
+        assert_eq!(vm.run_to_end(), Err(ExecutionError::Overflow { pc: 0 }));
+
+        let mut vm = Machine::<i64>::parse(SQUARE).unwrap();
+        vm.run_to_end().unwrap();
+        assert_eq!(vm.pop_output(), Some(1_219_070_632_396_864));
+
+        // The quine from day 9, on 32-bit cells
+        let quine = [
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        let mut memory = quine.to_vec();
+        memory.resize(102, 0);
+        let mut vm = Machine::<i32>::new(memory);
+        vm.run_to_end().unwrap();
+        assert_eq!(vm.collect::<Vec<_>>(), quine);
+
+        // Square the square, which only fits in 128 bits
+        let mut vm = Machine::<i128>::parse("3,13,1002,13,1,14,2,13,14,14,4,14,99,0,0").unwrap();
+        vm.push_input(1_219_070_632_396_864);
+        vm.run_to_end().unwrap();
+        assert_eq!(
+            vm.pop_output(),
+            Some(1_486_133_206_772_489_918_753_597_034_496)
+        );
+    }
+
+    #[test]
+    fn instruction() {
+        assert_eq!(21101i32.instruction(), 21101);
+        assert_eq!((10i128.pow(30) + 21101).instruction(), 21101);
+        assert_eq!((-3i128).instruction(), -3);
+    }
+
+    #[cfg(feature = "bigint")]
+    #[test]
+    fn bigint() {
+        use num_bigint::BigInt;
+
+        let mut vm = Machine::<BigInt>::parse("3,12,2,12,12,12,1005,12,2,99,0,0,0").unwrap();
+        let big: BigInt = "123456789012345678901234567890".parse().unwrap();
+        vm.push_input(big.clone());
+
+        // Squares forever, so stop after a few rounds
+        for _ in 0..11 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.get_memory(12), Ok(big.pow(32)));
+    }
+}